@@ -1,31 +1,48 @@
 using System;
 using System.Collections.Generic;
 using System.Drawing;
+using System.IO;
 using System.Linq;
 using System.Runtime.InteropServices;
 using System.Text;
+using System.Threading.Tasks;
 using System.Windows.Forms;
 using BizHawk.Client.Common;
 using BizHawk.Client.EmuHawk;
 
 namespace MidosHouse.OotrMultiworld {
     internal class Native {
-        [DllImport("multiworld")] internal static extern LobbyClientResult connect_ipv4();
-        [DllImport("multiworld")] internal static extern LobbyClientResult connect_ipv6();
+        [DllImport("multiworld")] internal static extern LobbyClientResult connect_configured();
+        [DllImport("multiworld")] internal static extern LobbyClientResult connect_server(OwnedStringHandle host, ushort port);
+        [DllImport("multiworld")] internal static extern LobbyClientResult connect_lan(OwnedStringHandle address);
+        [DllImport("multiworld")] internal static extern LanServersResult discover_lan_servers();
+        [DllImport("multiworld")] internal static extern void lan_servers_result_free(IntPtr lan_servers_res);
+        [DllImport("multiworld")] internal static extern bool lan_servers_result_is_ok(LanServersResult lan_servers_res);
+        [DllImport("multiworld")] internal static extern LanServers lan_servers_result_unwrap(IntPtr lan_servers_res);
+        [DllImport("multiworld")] internal static extern StringHandle lan_servers_result_debug_err(IntPtr lan_servers_res);
+        [DllImport("multiworld")] internal static extern void lan_servers_free(IntPtr lan_servers);
+        [DllImport("multiworld")] internal static extern ulong lan_servers_len(LanServers lan_servers);
+        [DllImport("multiworld")] internal static extern StringHandle lan_servers_address(LanServers lan_servers, ulong i);
+        [DllImport("multiworld")] internal static extern StringHandle lan_servers_description(LanServers lan_servers, ulong i);
         [DllImport("multiworld")] internal static extern void lobby_client_result_free(IntPtr lobby_client_res);
         [DllImport("multiworld")] internal static extern bool lobby_client_result_is_ok(LobbyClientResult lobby_client_res);
         [DllImport("multiworld")] internal static extern LobbyClient lobby_client_result_unwrap(IntPtr lobby_client_res);
         [DllImport("multiworld")] internal static extern void lobby_client_free(IntPtr lobby_client);
         [DllImport("multiworld")] internal static extern StringHandle lobby_client_result_debug_err(IntPtr lobby_client_res);
         [DllImport("multiworld")] internal static extern void string_free(IntPtr s);
+        [DllImport("multiworld")] internal static extern StringHandle lobby_client_server_addr(LobbyClient lobby_client);
         [DllImport("multiworld")] internal static extern ulong lobby_client_num_rooms(LobbyClient lobby_client);
         [DllImport("multiworld")] internal static extern StringHandle lobby_client_room_name(LobbyClient lobby_client, ulong i);
-        [DllImport("multiworld")] internal static extern StringResult lobby_client_try_recv_new_room(LobbyClient lobbyClient);
+        [DllImport("multiworld")] internal static extern StringHandle lobby_client_room_info(LobbyClient lobby_client, OwnedStringHandle room_name);
+        [DllImport("multiworld")] internal static extern bool lobby_client_room_password_protected(LobbyClient lobby_client, OwnedStringHandle room_name);
+        [DllImport("multiworld")] internal static extern OptMessageResult lobby_client_try_recv_message(LobbyClient lobby_client);
+        [DllImport("multiworld")] internal static extern void lobby_client_apply_message(LobbyClient lobby_client, IntPtr msg);
         [DllImport("multiworld")] internal static extern void string_result_free(IntPtr str_res);
         [DllImport("multiworld")] internal static extern bool string_result_is_ok(StringResult str_res);
         [DllImport("multiworld")] internal static extern StringHandle string_result_unwrap(IntPtr str_res);
         [DllImport("multiworld")] internal static extern StringHandle string_result_debug_err(IntPtr str_res);
-        [DllImport("multiworld")] internal static extern RoomClientResult lobby_client_room_connect(IntPtr lobby_client, OwnedStringHandle room_name, OwnedStringHandle password);
+        [DllImport("multiworld")] internal static extern RoomClientResult lobby_client_room_connect(IntPtr lobby_client, OwnedStringHandle room_name, OwnedStringHandle password, OwnedStringHandle spoiler_log);
+        [DllImport("multiworld")] internal static extern RoomClientResult lobby_client_room_resume(IntPtr lobby_client, OwnedStringHandle room_name, IntPtr token);
         [DllImport("multiworld")] internal static extern void room_client_result_free(IntPtr room_client_res);
         [DllImport("multiworld")] internal static extern bool room_client_result_is_ok(RoomClientResult room_client_res);
         [DllImport("multiworld")] internal static extern RoomClient room_client_result_unwrap(IntPtr room_client_res);
@@ -37,6 +54,7 @@ namespace MidosHouse.OotrMultiworld {
         [DllImport("multiworld")] internal static extern StringHandle unit_result_debug_err(IntPtr unit_res);
         [DllImport("multiworld")] internal static extern UnitResult room_client_reset_player_id(RoomClient room_client);
         [DllImport("multiworld")] internal static extern UnitResult room_client_set_player_name(RoomClient room_client, IntPtr name);
+        [DllImport("multiworld")] internal static extern StringHandle room_client_server_addr(RoomClient room_client);
         [DllImport("multiworld")] internal static extern StringHandle room_client_format_state(RoomClient room_client);
         [DllImport("multiworld")] internal static extern OptMessageResult room_client_try_recv_message(RoomClient room_client);
         [DllImport("multiworld")] internal static extern void opt_message_result_free(IntPtr opt_msg_res);
@@ -48,11 +66,20 @@ namespace MidosHouse.OotrMultiworld {
         [DllImport("multiworld")] internal static extern byte message_effect_type(ServerMessage msg);
         [DllImport("multiworld")] internal static extern byte message_player_id(ServerMessage msg);
         [DllImport("multiworld")] internal static extern IntPtr message_player_name(ServerMessage msg);
+        [DllImport("multiworld")] internal static extern StringHandle message_room_name(ServerMessage msg);
+        [DllImport("multiworld")] internal static extern long message_restart_eta(ServerMessage msg);
+        [DllImport("multiworld")] internal static extern StringHandle message_warning(ServerMessage msg);
         [DllImport("multiworld")] internal static extern void room_client_apply_message(RoomClient room_client, IntPtr msg);
         [DllImport("multiworld")] internal static extern UnitResult room_client_send_item(RoomClient room_client, uint key, ushort kind, byte target_world);
         [DllImport("multiworld")] internal static extern ushort room_client_item_queue_len(RoomClient room_client);
         [DllImport("multiworld")] internal static extern ushort room_client_item_kind_at_index(RoomClient room_client, ushort index);
         [DllImport("multiworld")] internal static extern IntPtr room_client_get_player_name(RoomClient room_client, byte world);
+        [DllImport("multiworld")] internal static extern IntPtr room_client_resumption_token(RoomClient room_client);
+        [DllImport("multiworld")] internal static extern IntPtr room_client_owner_token(RoomClient room_client);
+        [DllImport("multiworld")] internal static extern UnitResult room_client_claim_ownership(RoomClient room_client, IntPtr token);
+        [DllImport("multiworld")] internal static extern StringHandle room_client_format_chat(RoomClient room_client);
+        [DllImport("multiworld")] internal static extern UnitResult room_client_send_chat_message(RoomClient room_client, OwnedStringHandle text);
+        [DllImport("multiworld")] internal static extern LobbyClientResult room_client_leave(IntPtr room_client);
     }
 
     internal class StringHandle : SafeHandle {
@@ -92,19 +119,46 @@ namespace MidosHouse.OotrMultiworld {
             return true;
         }
 
+        internal StringHandle ServerAddr() => Native.lobby_client_server_addr(this);
         internal ulong NumRooms() => Native.lobby_client_num_rooms(this);
         internal StringHandle RoomName(ulong i) => Native.lobby_client_room_name(this, i);
-        internal StringResult TryRecvNewRoom() => Native.lobby_client_try_recv_new_room(this);
 
-        internal RoomClientResult CreateJoinRoom(string roomName, string password) {
+        internal StringHandle RoomInfo(string roomName) {
+            using (var nameHandle = new OwnedStringHandle(roomName)) {
+                return Native.lobby_client_room_info(this, nameHandle);
+            }
+        }
+
+        internal bool RoomPasswordProtected(string roomName) {
+            using (var nameHandle = new OwnedStringHandle(roomName)) {
+                return Native.lobby_client_room_password_protected(this, nameHandle);
+            }
+        }
+
+        internal OptMessageResult TryRecv() => Native.lobby_client_try_recv_message(this);
+
+        internal RoomClientResult CreateJoinRoom(string roomName, string password, string spoilerLog) {
             using (var nameHandle = new OwnedStringHandle(roomName)) {
                 using (var passwordHandle = new OwnedStringHandle(password)) {
-                    var res = Native.lobby_client_room_connect(this.handle, nameHandle, passwordHandle);
-                    this.handle = IntPtr.Zero; // lobby_client_room_connect takes ownership
-                    return res;
+                    using (var spoilerLogHandle = new OwnedStringHandle(spoilerLog)) {
+                        var res = Native.lobby_client_room_connect(this.handle, nameHandle, passwordHandle, spoilerLogHandle);
+                        this.handle = IntPtr.Zero; // lobby_client_room_connect takes ownership
+                        return res;
+                    }
                 }
             }
         }
+
+        internal RoomClientResult Resume(string roomName, byte[] token) {
+            using (var nameHandle = new OwnedStringHandle(roomName)) {
+                var tokenPtr = Marshal.AllocHGlobal(16);
+                Marshal.Copy(token, 0, tokenPtr, 16);
+                var res = Native.lobby_client_room_resume(this.handle, nameHandle, tokenPtr);
+                this.handle = IntPtr.Zero; // lobby_client_room_resume takes ownership
+                Marshal.FreeHGlobal(tokenPtr);
+                return res;
+            }
+        }
     }
 
     internal class LobbyClientResult : SafeHandle {
@@ -136,6 +190,54 @@ namespace MidosHouse.OotrMultiworld {
         }
     }
 
+    internal class LanServers : SafeHandle {
+        internal LanServers() : base(IntPtr.Zero, true) {}
+
+        public override bool IsInvalid {
+            get { return this.handle == IntPtr.Zero; }
+        }
+
+        protected override bool ReleaseHandle() {
+            if (!this.IsInvalid) {
+                Native.lan_servers_free(this.handle);
+            }
+            return true;
+        }
+
+        internal ulong Len() => Native.lan_servers_len(this);
+        internal StringHandle Address(ulong i) => Native.lan_servers_address(this, i);
+        internal StringHandle Description(ulong i) => Native.lan_servers_description(this, i);
+    }
+
+    internal class LanServersResult : SafeHandle {
+        internal LanServersResult() : base(IntPtr.Zero, true) {}
+
+        public override bool IsInvalid {
+            get { return this.handle == IntPtr.Zero; }
+        }
+
+        protected override bool ReleaseHandle() {
+            if (!this.IsInvalid) {
+                Native.lan_servers_result_free(this.handle);
+            }
+            return true;
+        }
+
+        internal bool IsOk() => Native.lan_servers_result_is_ok(this);
+
+        internal LanServers Unwrap() {
+            var lanServers = Native.lan_servers_result_unwrap(this.handle);
+            this.handle = IntPtr.Zero; // lan_servers_result_unwrap takes ownership
+            return lanServers;
+        }
+
+        internal StringHandle DebugErr() {
+            var err = Native.lan_servers_result_debug_err(this.handle);
+            this.handle = IntPtr.Zero; // lan_servers_result_debug_err takes ownership
+            return err;
+        }
+    }
+
     internal class OptMessageResult : SafeHandle {
         internal OptMessageResult() : base(IntPtr.Zero, true) {}
 
@@ -202,7 +304,49 @@ namespace MidosHouse.OotrMultiworld {
             return name.ToList();
         }
 
+        internal byte[]? ResumptionToken() {
+            var tokenPtr = Native.room_client_resumption_token(this);
+            if (tokenPtr == IntPtr.Zero) {
+                return null;
+            }
+            var token = new byte[16];
+            Marshal.Copy(tokenPtr, token, 0, 16);
+            return token;
+        }
+
+        internal byte[]? OwnerToken() {
+            var tokenPtr = Native.room_client_owner_token(this);
+            if (tokenPtr == IntPtr.Zero) {
+                return null;
+            }
+            var token = new byte[16];
+            Marshal.Copy(tokenPtr, token, 0, 16);
+            return token;
+        }
+
+        internal UnitResult ClaimOwnership(byte[] token) {
+            var tokenPtr = Marshal.AllocHGlobal(16);
+            Marshal.Copy(token, 0, tokenPtr, 16);
+            var res = Native.room_client_claim_ownership(this, tokenPtr);
+            Marshal.FreeHGlobal(tokenPtr);
+            return res;
+        }
+
+        internal UnitResult SendChatMessage(string text) {
+            using (var textHandle = new OwnedStringHandle(text)) {
+                return Native.room_client_send_chat_message(this, textHandle);
+            }
+        }
+
+        internal LobbyClientResult Leave() {
+            var res = Native.room_client_leave(this.handle);
+            this.handle = IntPtr.Zero; // room_client_leave takes ownership
+            return res;
+        }
+
+        internal StringHandle ServerAddr() => Native.room_client_server_addr(this);
         internal StringHandle State() => Native.room_client_format_state(this);
+        internal StringHandle Chat() => Native.room_client_format_chat(this);
         internal OptMessageResult TryRecv() => Native.room_client_try_recv_message(this);
         internal UnitResult SendItem(uint key, ushort kind, byte targetWorld) => Native.room_client_send_item(this, key, kind, targetWorld);
         internal ushort ItemQueueLen() => Native.room_client_item_queue_len(this);
@@ -261,6 +405,15 @@ namespace MidosHouse.OotrMultiworld {
             return name.ToList();
         }
 
+        internal StringHandle RoomName() => Native.message_room_name(this);
+        internal DateTime RestartEta() => DateTimeOffset.FromUnixTimeSeconds(Native.message_restart_eta(this)).LocalDateTime;
+        internal StringHandle Warning() => Native.message_warning(this);
+
+        internal void Apply(LobbyClient lobbyClient) {
+            Native.lobby_client_apply_message(lobbyClient, this.handle);
+            this.handle = IntPtr.Zero; // lobby_client_apply_message takes ownership of the message
+        }
+
         internal void Apply(RoomClient roomClient) {
             Native.room_client_apply_message(roomClient, this.handle);
             this.handle = IntPtr.Zero; // room_client_apply_message takes ownership of the message
@@ -345,13 +498,28 @@ namespace MidosHouse.OotrMultiworld {
         private ComboBox rooms = new ComboBox();
         private TextBox password = new TextBox();
         private Button createJoinButton = new Button();
+        private Button spoilerLogButton = new Button();
         private Label roomState = new Label();
-
+        private Label roomInfo = new Label();
+        private TextBox chatLog = new TextBox();
+        private TextBox chatInput = new TextBox();
+        private Button chatSendButton = new Button();
+        private Button leaveButton = new Button();
+        private ComboBox servers = new ComboBox();
+
+        // the address of each entry in the server list, with null for the server from the client config (the public server by default)
+        private List<string?> serverAddresses = new List<string?> { null };
+        private string? lanAddress;
+        private bool discoveringLanServers = false;
         private LobbyClient? lobbyClient;
         private RoomClient? roomClient;
         private uint? coopContextAddr;
         private byte? playerID;
         private List<byte> playerName = new List<byte> { 0xdf, 0xdf, 0xdf, 0xdf, 0xdf, 0xdf, 0xdf, 0xdf };
+        private string? roomName;
+        private string? spoilerLogPath;
+        private byte[]? resumptionToken;
+        private byte[]? ownerToken;
 
         public ApiContainer? _apiContainer { get; set; }
         private ApiContainer APIs => _apiContainer ?? throw new NullReferenceException();
@@ -389,7 +557,7 @@ namespace MidosHouse.OotrMultiworld {
             password.UseSystemPasswordChar = true;
             //TODO (.net 5) add PlaceholderText (“Password”)
             this.password.TextChanged += (s, e) => {
-                this.createJoinButton.Enabled = this.rooms.Enabled && this.rooms.Text.Length > 0 && this.password.Text.Length > 0;
+                this.LobbyStateChanged();
             };
             this.Controls.Add(this.password);
 
@@ -400,7 +568,19 @@ namespace MidosHouse.OotrMultiworld {
             this.createJoinButton.Enabled = false;
             this.createJoinButton.Click += (s, e) => {
                 if (this.lobbyClient != null) {
-                    using (var res = this.lobbyClient.CreateJoinRoom(this.rooms.Text, this.password.Text)) {
+                    this.roomName = this.rooms.Text;
+                    this.resumptionToken = null;
+                    this.ownerToken = null;
+                    var spoilerLog = "";
+                    if (this.spoilerLogPath != null) {
+                        try {
+                            spoilerLog = File.ReadAllText(this.spoilerLogPath);
+                        } catch (IOException ex) {
+                            Error($"failed to read spoiler log: {ex.Message}");
+                            return;
+                        }
+                    }
+                    using (var res = this.lobbyClient.CreateJoinRoom(this.rooms.Text, this.password.Text, spoilerLog)) {
                         if (res.IsOk()) {
                             JoinRoom(res.Unwrap());
                         } else {
@@ -419,6 +599,83 @@ namespace MidosHouse.OotrMultiworld {
             this.roomState.Visible = false;
             this.Controls.Add(this.roomState);
 
+            this.roomInfo.TabIndex = 5;
+            this.roomInfo.Location = new Point(12, 154);
+            this.roomInfo.AutoSize = true;
+            this.Controls.Add(this.roomInfo);
+
+            this.spoilerLogButton.TabIndex = 6;
+            this.spoilerLogButton.Location = new Point(120, 119);
+            this.spoilerLogButton.AutoSize = true;
+            this.spoilerLogButton.Text = "Attach spoiler log…";
+            this.spoilerLogButton.Visible = false;
+            this.spoilerLogButton.Click += (s, e) => {
+                using (var dialog = new OpenFileDialog()) {
+                    dialog.Filter = "Spoiler logs (*.json)|*.json";
+                    if (dialog.ShowDialog() == DialogResult.OK) {
+                        this.spoilerLogPath = dialog.FileName;
+                        this.LobbyStateChanged();
+                    }
+                }
+            };
+            this.Controls.Add(this.spoilerLogButton);
+
+            this.chatLog.TabIndex = 7;
+            this.chatLog.Location = new Point(12, 154);
+            this.chatLog.Size = new Size(485, 60);
+            this.chatLog.Multiline = true;
+            this.chatLog.ReadOnly = true;
+            this.chatLog.ScrollBars = ScrollBars.Vertical;
+            this.chatLog.Visible = false;
+            this.Controls.Add(this.chatLog);
+
+            this.chatInput.TabIndex = 8;
+            this.chatInput.Location = new Point(12, 222);
+            this.chatInput.Size = new Size(400, 25);
+            this.chatInput.MaxLength = 500; // MAX_CHAT_MESSAGE_LEN in the multiworld crate
+            this.chatInput.Visible = false;
+            this.chatInput.KeyDown += (s, e) => {
+                if (e.KeyCode == Keys.Enter) {
+                    SendChatMessage();
+                    e.SuppressKeyPress = true;
+                }
+            };
+            this.Controls.Add(this.chatInput);
+
+            this.chatSendButton.TabIndex = 9;
+            this.chatSendButton.Location = new Point(418, 221);
+            this.chatSendButton.AutoSize = true;
+            this.chatSendButton.Text = "Send";
+            this.chatSendButton.Visible = false;
+            this.chatSendButton.Click += (s, e) => {
+                SendChatMessage();
+            };
+            this.Controls.Add(this.chatSendButton);
+
+            this.leaveButton.TabIndex = 10;
+            this.leaveButton.Location = new Point(11, 119);
+            this.leaveButton.AutoSize = true;
+            this.leaveButton.Text = "Leave room";
+            this.leaveButton.Visible = false;
+            this.leaveButton.Click += (s, e) => {
+                LeaveRoom();
+            };
+            this.Controls.Add(this.leaveButton);
+
+            this.servers.TabIndex = 11;
+            this.servers.Location = new Point(297, 6);
+            this.servers.Size = new Size(200, 25);
+            this.servers.DropDownStyle = ComboBoxStyle.DropDownList;
+            this.servers.Items.Add("Default server");
+            this.servers.SelectedIndex = 0;
+            this.servers.DropDown += (s, e) => {
+                RefreshLanServers();
+            };
+            this.servers.SelectionChangeCommitted += (s, e) => {
+                SwitchServer(this.serverAddresses[this.servers.SelectedIndex]);
+            };
+            this.Controls.Add(this.servers);
+
             ResumeLayout(true);
         }
 
@@ -435,31 +692,106 @@ namespace MidosHouse.OotrMultiworld {
                 SyncPlayerNames();
                 ShowUI();
             } else if (this.lobbyClient == null) {
-                using (var res6 = Native.connect_ipv6()) {
-                    if (res6.IsOk()) {
-                        OnConnect(res6.Unwrap());
-                    } else {
-                        using (var res4 = Native.connect_ipv4()) {
-                            if (res4.IsOk()) {
-                                OnConnect(res4.Unwrap());
-                            } else {
-                                //TODO TCP connections unavailable, try WebSocket instead. If that fails too, offer self-hosting/direct connections
-                                using (var err = res4.DebugErr()) {
-                                    this.state.Text = $"error: {err.AsString()}";
-                                }
-                                this.rooms.Items[0] = "Failed to load room list";
-                            }
-                        }
+                ConnectToServer();
+            }
+        }
+
+        private LobbyClientResult Connect() {
+            if (this.lanAddress != null) {
+                using (var addressHandle = new OwnedStringHandle(this.lanAddress)) {
+                    return Native.connect_lan(addressHandle);
+                }
+            }
+            return Native.connect_configured();
+        }
+
+        private void ConnectToServer() {
+            using (var res = Connect()) {
+                if (res.IsOk()) {
+                    OnConnect(res.Unwrap());
+                } else {
+                    //TODO TCP connections unavailable, try WebSocket instead
+                    using (var err = res.DebugErr()) {
+                        this.state.Text = $"error: {err.AsString()}";
                     }
+                    this.rooms.Items[0] = "Failed to load room list";
                 }
-                ShowUI();
             }
+            ShowUI();
+        }
+
+        private void RefreshLanServers() {
+            if (this.discoveringLanServers) {
+                return;
+            }
+            this.discoveringLanServers = true;
+            // discovery blocks while waiting for replies, so run it in the background and update the list on the UI thread once it's done
+            Task.Run(() => Native.discover_lan_servers()).ContinueWith(task => {
+                this.discoveringLanServers = false;
+                if (task.IsFaulted) {
+                    this.state.Text = $"failed to look for LAN servers: {task.Exception?.GetBaseException().Message}";
+                    return;
+                }
+                OnLanServers(task.Result);
+            }, TaskScheduler.FromCurrentSynchronizationContext());
+        }
+
+        private void OnLanServers(LanServersResult lanServersRes) {
+            using (var res = lanServersRes) {
+                if (!res.IsOk()) {
+                    using (var err = res.DebugErr()) {
+                        this.state.Text = $"failed to look for LAN servers: {err.AsString()}";
+                    }
+                    return;
+                }
+                using (var lanServers = res.Unwrap()) {
+                    SuspendLayout();
+                    this.servers.Items.Clear();
+                    this.serverAddresses.Clear();
+                    this.servers.Items.Add("Default server");
+                    this.serverAddresses.Add(null);
+                    var len = lanServers.Len();
+                    for (ulong i = 0; i < len; i++) {
+                        this.servers.Items.Add(lanServers.Description(i).AsString());
+                        this.serverAddresses.Add(lanServers.Address(i).AsString());
+                    }
+                    // keep the current server selectable even if it didn't reply this time
+                    if (this.lanAddress != null && !this.serverAddresses.Contains(this.lanAddress)) {
+                        this.servers.Items.Add(this.lanAddress);
+                        this.serverAddresses.Add(this.lanAddress);
+                    }
+                    this.servers.SelectedIndex = this.serverAddresses.IndexOf(this.lanAddress);
+                    ResumeLayout(true);
+                }
+            }
+        }
+
+        private void SwitchServer(string? address) {
+            if (address == this.lanAddress && this.lobbyClient != null) {
+                return;
+            }
+            this.lanAddress = address;
+            // tokens from the previous server aren't valid on the new one
+            this.roomName = null;
+            this.resumptionToken = null;
+            this.ownerToken = null;
+            if (this.lobbyClient != null) {
+                this.lobbyClient.Dispose();
+                this.lobbyClient = null;
+            }
+            SuspendLayout();
+            this.rooms.Items.Clear();
+            this.rooms.Items.Add("Loading room list…");
+            this.rooms.SelectedIndex = 0;
+            this.rooms.Enabled = false;
+            ResumeLayout(true);
+            ConnectToServer();
         }
 
         private void OnConnect(LobbyClient lobbyClient) {
             this.lobbyClient = lobbyClient;
             var numRooms = this.lobbyClient.NumRooms();
-            this.state.Text = $"Join or create a room:";
+            this.state.Text = $"Connected to {this.lobbyClient.ServerAddr().AsString()}. Join or create a room:";
             SuspendLayout();
             this.rooms.SelectedItem = null;
             this.rooms.Items.Clear();
@@ -478,16 +810,36 @@ namespace MidosHouse.OotrMultiworld {
                 return;
             }
             if (this.lobbyClient != null) {
-                using (var res = this.lobbyClient.TryRecvNewRoom()) {
-                    if (res.IsOk()) {
-                        using (var newRoom = res.Unwrap()) {
-                            var name = newRoom.AsString();
-                            if (name.Length > 0) {
-                                this.rooms.Items.Add(newRoom.AsString());
+                using (var res = this.lobbyClient.TryRecv()) {
+                    if (res.IsOkSome()) {
+                        using (var msg = res.UnwrapUnwrap()) {
+                            switch (msg.EffectType()) {
+                                case 2: { // adds a room to the lobby
+                                    this.rooms.Items.Add(msg.RoomName().AsString());
+                                    break;
+                                }
+                                case 3: { // removes a room from the lobby
+                                    this.rooms.Items.Remove(msg.RoomName().AsString());
+                                    break;
+                                }
+                                case 4: { // warns about an upcoming server restart
+                                    this.state.Text = $"The server is going to restart at {msg.RestartEta():t}. Please try again afterwards.";
+                                    break;
+                                }
+                                case 5: { // changes the metadata of a room in the lobby
+                                    break; // room info label is updated below
+                                }
+                                default: {
+                                    Error($"received unknown server message of effect type {msg.EffectType()}");
+                                    break;
+                                }
+                            }
+                            if (this.lobbyClient != null) {
+                                msg.Apply(this.lobbyClient);
                                 this.LobbyStateChanged();
                             }
                         }
-                    } else {
+                    } else if (res.IsErr()) {
                         using (var err = res.DebugErr()) {
                             Error(err.AsString());
                         }
@@ -516,6 +868,30 @@ namespace MidosHouse.OotrMultiworld {
                                     this.roomState.Text = this.roomClient.State().AsString();
                                     break;
                                 }
+                                case 4: { // warns about an upcoming server restart
+                                    this.state.Text = $"The server is going to restart at {msg.RestartEta():t}. You will need to reconnect afterwards.";
+                                    msg.Apply(this.roomClient);
+                                    break;
+                                }
+                                case 6: { // sets the token for reclaiming our world after a disconnect
+                                    msg.Apply(this.roomClient);
+                                    this.resumptionToken = this.roomClient.ResumptionToken();
+                                    break;
+                                }
+                                case 8: { // grants owner rights and sets the token for regaining them after a disconnect
+                                    msg.Apply(this.roomClient);
+                                    this.ownerToken = this.roomClient.OwnerToken();
+                                    break;
+                                }
+                                case 9: { // adds a message to the room's text chat
+                                    msg.Apply(this.roomClient);
+                                    UpdateChatLog();
+                                    break;
+                                }
+                                case 10: { // shows a non-fatal error
+                                    this.state.Text = $"warning: {msg.Warning().AsString()}";
+                                    break;
+                                }
                                 default: {
                                     Error($"received unknown server message of effect type {msg.EffectType()}");
                                     break;
@@ -524,11 +900,11 @@ namespace MidosHouse.OotrMultiworld {
                         }
                     } else if (res.IsErr()) {
                         using (var err = res.DebugErr()) {
-                            Error(err.AsString());
+                            Reconnect(err.AsString());
                         }
                     }
                 }
-                if (this.playerID != null && this.coopContextAddr != null) {
+                if (this.roomClient != null && this.playerID != null && this.coopContextAddr != null) {
                     var outgoingKey = APIs.Memory.ReadU32(this.coopContextAddr.Value + 0xc, "System Bus");
                     if (outgoingKey != 0) {
                         var kind = (ushort) APIs.Memory.ReadU16(this.coopContextAddr.Value + 0x10, "System Bus");
@@ -571,16 +947,87 @@ namespace MidosHouse.OotrMultiworld {
             this.lobbyClient = null;
             this.roomClient = client;
             SuspendLayout();
+            this.servers.Visible = false;
             this.rooms.Visible = false;
             this.password.Visible = false;
             this.createJoinButton.Visible = false;
+            this.spoilerLogButton.Visible = false;
+            this.roomInfo.Visible = false;
             this.roomState.Text = client.State().AsString();
             this.roomState.Visible = true;
+            UpdateChatLog();
+            this.chatLog.Visible = true;
+            this.chatInput.Visible = true;
+            this.chatSendButton.Visible = true;
+            this.leaveButton.Visible = true;
             ResumeLayout(true);
             ReadPlayerID();
             SyncPlayerNames();
         }
 
+        private void LeaveRoom() {
+            if (this.roomClient != null) {
+                using (var res = this.roomClient.Leave()) {
+                    this.roomClient = null;
+                    this.resumptionToken = null;
+                    this.ownerToken = null;
+                    if (res.IsOk()) {
+                        HideUI();
+                        OnConnect(res.Unwrap());
+                        ShowUI();
+                        this.LobbyStateChanged();
+                    } else {
+                        using (var err = res.DebugErr()) {
+                            Error(err.AsString());
+                        }
+                    }
+                }
+            }
+        }
+
+        // tries once to reclaim our world on a new connection, showing the original error if that fails
+        private void Reconnect(string msg) {
+            var roomName = this.roomName;
+            var token = this.resumptionToken;
+            this.resumptionToken = null;
+            if (this.roomClient != null) {
+                this.roomClient.Dispose();
+                this.roomClient = null;
+            }
+            if (roomName == null || token == null) {
+                Error(msg);
+                return;
+            }
+            using (var res = Connect()) {
+                if (res.IsOk() && Resume(res.Unwrap(), roomName, token)) {
+                    return;
+                }
+            }
+            Error(msg);
+        }
+
+        private bool Resume(LobbyClient lobbyClient, string roomName, byte[] token) {
+            using (lobbyClient) {
+                using (var res = lobbyClient.Resume(roomName, token)) {
+                    if (res.IsOk()) {
+                        JoinRoom(res.Unwrap());
+                        if (this.roomClient != null && this.ownerToken != null) {
+                            using (var claimRes = this.roomClient.ClaimOwnership(this.ownerToken)) {
+                                if (!claimRes.IsOk()) {
+                                    using (var err = claimRes.DebugErr()) {
+                                        Error(err.AsString());
+                                    }
+                                }
+                            }
+                        }
+                        return true;
+                    } else {
+                        return false;
+                    }
+                }
+            }
+        }
+
         private void ReadPlayerID() {
             if ((APIs.GameInfo.GetGameInfo()?.Name ?? "Null") == "Null") {
                 this.playerID = null;
@@ -651,17 +1098,50 @@ namespace MidosHouse.OotrMultiworld {
             }
         }
 
+        private void UpdateChatLog() {
+            if (this.roomClient != null) {
+                this.chatLog.Text = this.roomClient.Chat().AsString();
+                this.chatLog.SelectionStart = this.chatLog.Text.Length;
+                this.chatLog.ScrollToCaret();
+            }
+        }
+
+        private void SendChatMessage() {
+            if (this.roomClient != null && this.chatInput.Text.Trim().Length > 0) {
+                using (var res = this.roomClient.SendChatMessage(this.chatInput.Text)) {
+                    if (res.IsOk()) {
+                        this.chatInput.Text = "";
+                    } else {
+                        using (var err = res.DebugErr()) {
+                            Error(err.AsString());
+                        }
+                    }
+                }
+            }
+        }
+
         private void LobbyStateChanged() {
-            if (this.rooms.Enabled && this.rooms.Text.Length > 0) {
-                this.createJoinButton.Enabled = this.password.Text.Length > 0;
+            if (this.lobbyClient != null && this.rooms.Enabled && this.rooms.Text.Length > 0) {
                 if (this.rooms.Items.Contains(this.rooms.Text)) {
                     this.createJoinButton.Text = "Join";
+                    this.createJoinButton.Enabled = this.password.Text.Length > 0 || !this.lobbyClient.RoomPasswordProtected(this.rooms.Text);
+                    using (var info = this.lobbyClient.RoomInfo(this.rooms.Text)) {
+                        this.roomInfo.Text = info.AsString();
+                    }
                 } else {
                     this.createJoinButton.Text = "Create";
+                    this.createJoinButton.Enabled = true;
+                    this.roomInfo.Text = this.password.Text.Length > 0 ? "" : "The new room will not have a password.";
+                }
+                this.spoilerLogButton.Visible = true;
+                if (this.spoilerLogPath != null) {
+                    this.roomInfo.Text += $"{(this.roomInfo.Text.Length > 0 ? " " : "")}Spoiler log: {Path.GetFileName(this.spoilerLogPath)}";
                 }
             } else {
                 this.createJoinButton.Enabled = false;
                 this.createJoinButton.Text = "Create/Join";
+                this.spoilerLogButton.Visible = false;
+                this.roomInfo.Text = "";
             }
         }
 
@@ -676,23 +1156,38 @@ namespace MidosHouse.OotrMultiworld {
                 this.roomClient = null;
             }
             HideUI();
+            // allow switching to a different server after an error
+            this.servers.Visible = true;
         }
 
         private void HideUI() {
+            this.servers.Visible = false;
             this.rooms.Visible = false;
             this.password.Visible = false;
             this.createJoinButton.Visible = false;
+            this.spoilerLogButton.Visible = false;
+            this.roomInfo.Visible = false;
             this.roomState.Visible = false;
+            this.chatLog.Visible = false;
+            this.chatInput.Visible = false;
+            this.chatSendButton.Visible = false;
+            this.leaveButton.Visible = false;
         }
 
         private void ShowUI() {
             if (this.lobbyClient != null) {
+                this.servers.Visible = true;
                 this.rooms.Visible = true;
                 this.password.Visible = true;
                 this.createJoinButton.Visible = true;
+                this.roomInfo.Visible = true;
             }
             if (this.roomClient != null) {
                 this.roomState.Visible = true;
+                this.chatLog.Visible = true;
+                this.chatInput.Visible = true;
+                this.chatSendButton.Visible = true;
+                this.leaveButton.Visible = true;
             }
         }
     }
@@ -2,6 +2,10 @@
 
 use {
     std::{
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
         convert::{
             TryFrom as _,
             TryInto as _,
@@ -11,18 +15,45 @@ use {
             CString,
         },
         fmt,
-        net::TcpStream,
+        io::{
+            self,
+            prelude::*,
+        },
+        net::{
+            IpAddr,
+            SocketAddr,
+            TcpStream,
+            ToSocketAddrs,
+        },
         num::NonZeroU8,
+        ptr,
         slice,
         time::Duration,
     },
     async_proto::Protocol,
     libc::c_char,
+    rustls::{
+        ClientConnection,
+        StreamOwned,
+    },
     multiworld::{
+        CHAT_HISTORY_LEN,
+        ChatMessage,
         LobbyClientMessage,
         Player,
         RoomClientMessage,
         ServerMessage,
+        RoomInfo,
+        chat_message_error,
+        config::Config,
+        connect,
+        discovery::{
+            self,
+            LanServers,
+            format_lan_server,
+        },
+        format_chat_message,
+        format_room_info,
         format_room_state,
     },
 };
@@ -104,76 +135,244 @@ impl<T> DebugResultExt for DebugResult<T> {
     }
 }
 
+/// A connection to either the public server (over TLS if the server supports it) or a LAN server (unencrypted).
+#[derive(Debug)]
+enum Stream {
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn sock(&self) -> &TcpStream {
+        match self {
+            Self::Tls(stream) => &stream.sock,
+            Self::Tcp(stream) => stream,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tls(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tls(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tls(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LobbyClient {
-    tcp_stream: TcpStream,
+    stream: Stream,
+    /// The address of the server this client connected to, since the server's host name may resolve to several.
+    server_addr: SocketAddr,
     buf: Vec<u8>,
-    rooms: Vec<String>,
+    rooms: BTreeMap<String, RoomInfo>,
 }
 
 impl LobbyClient {
     fn try_read<T: Protocol>(&mut self) -> Result<Option<T>, async_proto::ReadError> {
-        self.tcp_stream.set_nonblocking(true)?;
-        T::try_read(&mut self.tcp_stream, &mut self.buf)
+        self.stream.sock().set_nonblocking(true)?;
+        T::try_read(&mut self.stream, &mut self.buf)
     }
 
     fn write(&mut self, msg: &impl Protocol) -> Result<(), async_proto::WriteError> {
-        self.tcp_stream.set_nonblocking(false)?;
-        msg.write_sync(&mut self.tcp_stream)
+        self.stream.sock().set_nonblocking(false)?;
+        msg.write_sync(&mut self.stream)
     }
 }
 
 #[derive(Debug)]
 pub struct RoomClient {
-    tcp_stream: TcpStream,
+    stream: Stream,
+    server_addr: SocketAddr,
     buf: Vec<u8>,
     players: Vec<Player>,
     num_unassigned_clients: u8,
     last_world: Option<NonZeroU8>,
     last_name: [u8; 8],
     item_queue: Vec<u16>,
+    resumption_token: Option<[u8; 16]>,
+    owner_token: Option<[u8; 16]>,
+    latencies: HashMap<NonZeroU8, Duration>,
+    chat_history: Vec<ChatMessage>,
 }
 
 impl RoomClient {
     fn try_read<T: Protocol>(&mut self) -> Result<Option<T>, async_proto::ReadError> {
-        self.tcp_stream.set_nonblocking(true)?;
-        T::try_read(&mut self.tcp_stream, &mut self.buf)
+        self.stream.sock().set_nonblocking(true)?;
+        T::try_read(&mut self.stream, &mut self.buf)
     }
 
     fn write(&mut self, msg: &impl Protocol) -> Result<(), async_proto::WriteError> {
-        self.tcp_stream.set_nonblocking(false)?;
-        msg.write_sync(&mut self.tcp_stream)
+        self.stream.sock().set_nonblocking(false)?;
+        msg.write_sync(&mut self.stream)
     }
 }
 
-#[no_mangle] pub extern "C" fn connect_ipv4() -> HandleOwned<DebugResult<LobbyClient>> {
-    HandleOwned::new(TcpStream::connect((multiworld::ADDRESS_V4, multiworld::PORT))
+fn set_timeouts(tcp_stream: &TcpStream) -> io::Result<()> {
+    tcp_stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    tcp_stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+    Ok(())
+}
+
+fn connect_to(addr: impl ToSocketAddrs, tls: bool) -> DebugResult<LobbyClient> {
+    let (tcp_stream, addr) = connect::tcp_sync(addr)?;
+    set_timeouts(&tcp_stream)?;
+    let mut stream = if tls {
+        let mut tls_stream = multiworld::connect_tls_sync(tcp_stream)?;
+        match tls_stream.conn.complete_io(&mut tls_stream.sock) {
+            Ok(_) => Stream::Tls(Box::new(tls_stream)),
+            Err(e) if multiworld::tls_unsupported(&e) => {
+                // the server doesn't have TLS set up yet, so reconnect without encryption
+                let tcp_stream = TcpStream::connect(addr)?;
+                set_timeouts(&tcp_stream)?;
+                Stream::Tcp(tcp_stream)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        Stream::Tcp(tcp_stream)
+    };
+    let rooms = multiworld::handshake_sync(&mut stream)?;
+    Ok(LobbyClient {
+        buf: Vec::default(),
+        server_addr: addr,
+        rooms,
+        stream,
+    })
+}
+
+fn connect_public() -> DebugResult<LobbyClient> {
+    connect_to(&connect::public_server_addrs()[..], true)
+}
+
+/// Connects to the server from the client config, falling back to the public server if none is configured.
+#[no_mangle] pub extern "C" fn connect_configured() -> HandleOwned<DebugResult<LobbyClient>> {
+    HandleOwned::new(Config::load_sync()
         .map_err(DebugError::from)
-        .and_then(|mut tcp_stream| {
-            tcp_stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-            tcp_stream.set_write_timeout(Some(Duration::from_secs(30)))?;
-            let rooms = multiworld::handshake_sync(&mut tcp_stream)?;
-            Ok(LobbyClient {
-                buf: Vec::default(),
-                rooms: rooms.into_iter().collect(),
-                tcp_stream,
-            })
+        .and_then(|config| if let Some((host, port)) = config.custom_server() {
+            connect_to((host, port), false)
+        } else {
+            connect_public()
         }))
 }
 
-#[no_mangle] pub extern "C" fn connect_ipv6() -> HandleOwned<DebugResult<LobbyClient>> {
-    HandleOwned::new(TcpStream::connect((multiworld::ADDRESS_V6, multiworld::PORT))
+/// Connects to the given server, resolving `host` if it's a host name. An empty `host` means the public server, and a `port` of 0 means `multiworld::PORT`.
+///
+/// Only the public server has a TLS certificate, so connections to other servers are unencrypted.
+///
+/// # Safety
+///
+/// `host` must be a null-terminated UTF-8 string.
+#[no_mangle] pub unsafe extern "C" fn connect_server(host: *const c_char, port: u16) -> HandleOwned<DebugResult<LobbyClient>> {
+    let host = CStr::from_ptr(host).to_str().expect("host was not valid UTF-8");
+    HandleOwned::new(if host.is_empty() {
+        connect_public()
+    } else {
+        connect_to((host, if port == 0 { multiworld::PORT } else { port }), false)
+    })
+}
+
+/// Connects to a LAN server, such as one found by `discover_lan_servers`. LAN servers don't have a TLS certificate, so the connection is unencrypted.
+///
+/// # Safety
+///
+/// `address` must be a null-terminated UTF-8 string.
+#[no_mangle] pub unsafe extern "C" fn connect_lan(address: *const c_char) -> HandleOwned<DebugResult<LobbyClient>> {
+    let address = CStr::from_ptr(address).to_str().expect("address was not valid UTF-8");
+    HandleOwned::new(address.parse::<IpAddr>()
         .map_err(DebugError::from)
-        .and_then(|mut tcp_stream| {
-            tcp_stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-            tcp_stream.set_write_timeout(Some(Duration::from_secs(30)))?;
-            let rooms = multiworld::handshake_sync(&mut tcp_stream)?;
-            Ok(LobbyClient {
-                buf: Vec::default(),
-                rooms: rooms.into_iter().collect(),
-                tcp_stream,
-            })
-        }))
+        .and_then(|address| connect_to((address, multiworld::PORT), false)))
+}
+
+/// Looks for servers on the local network. Blocks for a short time while waiting for replies.
+#[no_mangle] pub extern "C" fn discover_lan_servers() -> HandleOwned<DebugResult<LanServers>> {
+    HandleOwned::new(discovery::discover_sync().map_err(DebugError::from))
+}
+
+/// # Safety
+///
+/// `lan_servers_res` must point at a valid `DebugResult<LanServers>`. This function takes ownership of the `DebugResult`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_result_free(lan_servers_res: HandleOwned<DebugResult<LanServers>>) {
+    let _ = lan_servers_res.into_box();
+}
+
+/// # Safety
+///
+/// `lan_servers_res` must point at a valid `DebugResult<LanServers>`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_result_is_ok(lan_servers_res: *const DebugResult<LanServers>) -> FfiBool {
+    (&*lan_servers_res).is_ok().into()
+}
+
+/// # Safety
+///
+/// `lan_servers_res` must point at a valid `DebugResult<LanServers>`. This function takes ownership of the `DebugResult`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_result_unwrap(lan_servers_res: HandleOwned<DebugResult<LanServers>>) -> HandleOwned<LanServers> {
+    HandleOwned::new(lan_servers_res.into_box().debug_unwrap())
+}
+
+/// # Safety
+///
+/// `lan_servers_res` must point at a valid `DebugResult<LanServers>`. This function takes ownership of the `DebugResult`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_result_debug_err(lan_servers_res: HandleOwned<DebugResult<LanServers>>) -> StringHandle {
+    StringHandle::from_string(lan_servers_res.into_box().unwrap_err())
+}
+
+/// # Safety
+///
+/// `lan_servers` must point at a valid `LanServers`. This function takes ownership of the `LanServers`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_free(lan_servers: HandleOwned<LanServers>) {
+    let _ = lan_servers.into_box();
+}
+
+/// # Safety
+///
+/// `lan_servers` must point at a valid `LanServers`.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_len(lan_servers: *const LanServers) -> u64 {
+    (&*lan_servers).len().try_into().expect("too many LAN servers")
+}
+
+/// Returns the IP address of the server, to be passed to `connect_lan`.
+///
+/// # Safety
+///
+/// `lan_servers` must point at a valid `LanServers`.
+///
+/// # Panics
+///
+/// If `i` is out of range.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_address(lan_servers: *const LanServers, i: u64) -> StringHandle {
+    StringHandle::from_string((&*lan_servers).keys().nth(usize::try_from(i).expect("index out of range")).expect("index out of range"))
+}
+
+/// Returns a human-readable description of the server and its rooms.
+///
+/// # Safety
+///
+/// `lan_servers` must point at a valid `LanServers`.
+///
+/// # Panics
+///
+/// If `i` is out of range.
+#[no_mangle] pub unsafe extern "C" fn lan_servers_description(lan_servers: *const LanServers, i: u64) -> StringHandle {
+    let (&addr, rooms) = (&*lan_servers).iter().nth(usize::try_from(i).expect("index out of range")).expect("index out of range");
+    StringHandle::from_string(format_lan_server(addr, rooms))
 }
 
 /// # Safety
@@ -218,6 +417,15 @@ impl RoomClient {
     let _ = CString::from_raw(s.0);
 }
 
+/// Returns the address of the server this client is connected to, e.g. for display to the user.
+///
+/// # Safety
+///
+/// `lobby_client` must point at a valid `LobbyClient`.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_server_addr(lobby_client: *const LobbyClient) -> StringHandle {
+    StringHandle::from_string((&*lobby_client).server_addr)
+}
+
 /// # Safety
 ///
 /// `lobby_client` must point at a valid `LobbyClient`.
@@ -233,30 +441,61 @@ impl RoomClient {
 ///
 /// If `i` is out of range.
 #[no_mangle] pub unsafe extern "C" fn lobby_client_room_name(lobby_client: *const LobbyClient, i: u64) -> StringHandle {
-    StringHandle::from_string(&(&*lobby_client).rooms[usize::try_from(i).expect("index out of range")])
+    StringHandle::from_string((&*lobby_client).rooms.keys().nth(usize::try_from(i).expect("index out of range")).expect("index out of range"))
+}
+
+/// Returns a human-readable description of the room's metadata, or an empty string if there is no room with the given name.
+///
+/// # Safety
+///
+/// `lobby_client` must point at a valid `LobbyClient`. `room_name` must be a null-terminated UTF-8 string.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_room_info(lobby_client: *const LobbyClient, room_name: *const c_char) -> StringHandle {
+    let room_name = CStr::from_ptr(room_name).to_str().expect("room name was not valid UTF-8");
+    StringHandle::from_string((&*lobby_client).rooms.get(room_name).map(format_room_info).unwrap_or_default())
 }
 
-/// Attempts to read a message from the server if one is available, without blocking if there is not. Returns the name of the newly opened room, or an empty string if none was opened.
+/// Returns whether a password is required to join the given room. Returns `false` if there is no room with the given name.
+///
+/// # Safety
+///
+/// `lobby_client` must point at a valid `LobbyClient`. `room_name` must be a null-terminated UTF-8 string.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_room_password_protected(lobby_client: *const LobbyClient, room_name: *const c_char) -> FfiBool {
+    let room_name = CStr::from_ptr(room_name).to_str().expect("room name was not valid UTF-8");
+    (&*lobby_client).rooms.get(room_name).map_or(false, |info| info.password_protected).into()
+}
+
+/// Attempts to read a message from the server if one is available, without blocking if there is not.
+///
+/// Heartbeats are answered automatically and not returned.
 ///
 /// # Safety
 ///
 /// `lobby_client` must point at a valid `LobbyClient`.
-#[no_mangle] pub unsafe extern "C" fn lobby_client_try_recv_new_room(lobby_client: *mut LobbyClient) -> HandleOwned<DebugResult<String>> {
+#[no_mangle] pub unsafe extern "C" fn lobby_client_try_recv_message(lobby_client: *mut LobbyClient) -> HandleOwned<DebugResult<Option<ServerMessage>>> {
     let lobby_client = &mut *lobby_client;
     HandleOwned::new(match lobby_client.try_read() {
         Ok(Some(ServerMessage::Error(e))) => Err(DebugError(e)),
-        Ok(Some(ServerMessage::NewRoom(name))) => {
-            if let Err(idx) = lobby_client.rooms.binary_search(&name) {
-                lobby_client.rooms.insert(idx, name.clone());
-            }
-            Ok(name)
-        }
+        Ok(Some(msg @ (ServerMessage::NewRoom(_, _) | ServerMessage::DeleteRoom(_) | ServerMessage::PrepareRestart(_) | ServerMessage::UpdateRoom(_, _)))) => Ok(Some(msg)),
+        Ok(Some(ServerMessage::Ping)) => lobby_client.write(&LobbyClientMessage::Pong).map(|()| None).map_err(DebugError::from),
         Ok(Some(msg)) => Err(DebugError(format!("{msg:?}"))),
-        Ok(None) => Ok(String::default()),
+        Ok(None) => Ok(None),
         Err(e) => Err(DebugError::from(e)),
     })
 }
 
+/// # Safety
+///
+/// `lobby_client` must point at a valid `LobbyClient`, and `msg` must point at a valid `ServerMessage`. This function takes ownership of the `ServerMessage`.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_apply_message(lobby_client: *mut LobbyClient, msg: HandleOwned<ServerMessage>) {
+    let lobby_client = &mut *lobby_client;
+    match *msg.into_box() {
+        ServerMessage::NewRoom(name, info) | ServerMessage::UpdateRoom(name, info) => { lobby_client.rooms.insert(name, info); }
+        ServerMessage::DeleteRoom(name) => { lobby_client.rooms.remove(&name); }
+        ServerMessage::PrepareRestart(_) => {}
+        _ => unreachable!(),
+    }
+}
+
 /// # Safety
 ///
 /// `str_res` must point at a valid `DebugResult<String>`. This function takes ownership of the `DebugResult`.
@@ -287,38 +526,64 @@ impl RoomClient {
 
 /// # Safety
 ///
-/// `lobby_client` must point at a valid `LobbyClient`. This function takes ownership of the `LobbyClient`. `room_name` and `password` must be null-terminated UTF-8 strings.
-#[no_mangle] pub unsafe extern "C" fn lobby_client_room_connect(lobby_client: HandleOwned<LobbyClient>, room_name: *const c_char, password: *const c_char) -> HandleOwned<DebugResult<RoomClient>> {
-    let mut lobby_client = lobby_client.into_box();
+/// `lobby_client` must point at a valid `LobbyClient`. This function takes ownership of the `LobbyClient`. `room_name`, `password`, and `spoiler_log` must be null-terminated UTF-8 strings. `spoiler_log` may be empty.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_room_connect(lobby_client: HandleOwned<LobbyClient>, room_name: *const c_char, password: *const c_char, spoiler_log: *const c_char) -> HandleOwned<DebugResult<RoomClient>> {
+    let lobby_client = lobby_client.into_box();
     let name = CStr::from_ptr(room_name).to_str().expect("room name was not valid UTF-8").to_owned();
-    let password = CStr::from_ptr(password).to_str().expect("room name was not valid UTF-8");
-    HandleOwned::new(if lobby_client.rooms.contains(&name) {
-        lobby_client.write(&LobbyClientMessage::JoinRoom { name, password: password.to_owned() })
+    let password = CStr::from_ptr(password).to_str().expect("room name was not valid UTF-8").to_owned();
+    let spoiler_log = CStr::from_ptr(spoiler_log).to_str().expect("spoiler log was not valid UTF-8");
+    let spoiler_log = (!spoiler_log.is_empty()).then(|| spoiler_log.to_owned());
+    HandleOwned::new(if lobby_client.rooms.contains_key(&name) {
+        enter_room(lobby_client, LobbyClientMessage::JoinRoom { name, password, spoiler_log })
     } else {
-        lobby_client.write(&LobbyClientMessage::CreateRoom { name, password: password.to_owned() })
-    }.map_err(DebugError::from)
+        enter_room(lobby_client, LobbyClientMessage::CreateRoom { name, password, spoiler_log })
+    })
+}
+
+/// # Safety
+///
+/// `lobby_client` must point at a valid `LobbyClient`. This function takes ownership of the `LobbyClient`. `room_name` must be a null-terminated UTF-8 string. `token` must point at 16 bytes previously returned by `room_client_resumption_token`.
+#[no_mangle] pub unsafe extern "C" fn lobby_client_room_resume(lobby_client: HandleOwned<LobbyClient>, room_name: *const c_char, token: *const u8) -> HandleOwned<DebugResult<RoomClient>> {
+    let lobby_client = lobby_client.into_box();
+    let room = CStr::from_ptr(room_name).to_str().expect("room name was not valid UTF-8").to_owned();
+    let token = *token.cast::<[u8; 16]>();
+    HandleOwned::new(enter_room(lobby_client, LobbyClientMessage::Resume { room, token }))
+}
+
+fn enter_room(mut lobby_client: Box<LobbyClient>, msg: LobbyClientMessage) -> DebugResult<RoomClient> {
+    lobby_client.write(&msg)
+    .map_err(DebugError::from)
     .and_then(|()| if lobby_client.buf.is_empty() {
         Ok(())
     } else {
         Err(DebugError(format!("residual data in lobby client buffer upon room join"))) //TODO add blocking read with buffer prefix to async-proto?
     })
     .and_then(|()| loop {
-        break match ServerMessage::read_sync(&mut lobby_client.tcp_stream) {
+        break match ServerMessage::read_sync(&mut lobby_client.stream) {
             Ok(ServerMessage::Error(e)) => Err(DebugError(e)),
-            Ok(ServerMessage::NewRoom(_)) => continue,
-            Ok(ServerMessage::EnterRoom { players, num_unassigned_clients }) => Ok((players, num_unassigned_clients)),
+            Ok(ServerMessage::NewRoom(_, _) | ServerMessage::DeleteRoom(_) | ServerMessage::PrepareRestart(_) | ServerMessage::UpdateRoom(_, _)) => continue,
+            // a ping sent before the server received our message
+            Ok(ServerMessage::Ping) => match lobby_client.write(&LobbyClientMessage::Pong) {
+                Ok(()) => continue,
+                Err(e) => Err(DebugError::from(e)),
+            },
+            Ok(ServerMessage::EnterRoom { players, num_unassigned_clients, chat_history }) => Ok((players, num_unassigned_clients, chat_history)),
             Ok(msg) => Err(DebugError(format!("{msg:?}"))),
             Err(e) => Err(DebugError::from(e)),
         }
     })
-    .map(|(players, num_unassigned_clients)| RoomClient {
-        players, num_unassigned_clients,
-        tcp_stream: lobby_client.tcp_stream,
+    .map(|(players, num_unassigned_clients, chat_history)| RoomClient {
+        players, num_unassigned_clients, chat_history,
+        stream: lobby_client.stream,
+        server_addr: lobby_client.server_addr,
         buf: Vec::default(),
         last_world: None,
         last_name: Player::DEFAULT_NAME,
         item_queue: Vec::default(),
-    }))
+        resumption_token: None,
+        owner_token: None,
+        latencies: HashMap::default(),
+    })
 }
 
 /// # Safety
@@ -435,11 +700,13 @@ impl RoomClient {
 /// `room_client` must point at a valid `RoomClient`.
 #[no_mangle] pub unsafe extern "C" fn room_client_format_state(room_client: *const RoomClient) -> StringHandle {
     let room_client = &*room_client;
-    StringHandle::from_string(format_room_state(&room_client.players, room_client.num_unassigned_clients, room_client.last_world))
+    StringHandle::from_string(format_room_state(&room_client.players, room_client.num_unassigned_clients, room_client.last_world, &room_client.latencies))
 }
 
 /// Attempts to read a message from the server if one is available, without blocking if there is not.
 ///
+/// Heartbeats are answered automatically and not returned.
+///
 /// # Safety
 ///
 /// `room_client` must point at a valid `RoomClient`.
@@ -447,6 +714,7 @@ impl RoomClient {
     let room_client = &mut *room_client;
     HandleOwned::new(match room_client.try_read() {
         Ok(Some(ServerMessage::Error(e))) => Err(DebugError(e)),
+        Ok(Some(ServerMessage::Ping)) => room_client.write(&RoomClientMessage::Pong).map(|()| None).map_err(DebugError::from),
         Ok(opt_msg) => Ok(opt_msg),
         Err(e) => Err(DebugError::from(e)),
     })
@@ -504,8 +772,7 @@ impl RoomClient {
 #[no_mangle] pub unsafe extern "C" fn message_effect_type(msg: *const ServerMessage) -> u8 {
     let msg = &*msg;
     match msg {
-        ServerMessage::Error(_) |
-        ServerMessage::NewRoom(_) => unreachable!(),
+        ServerMessage::Error(_) => unreachable!(),
         ServerMessage::EnterRoom { .. } |
         ServerMessage::PlayerId(_) |
         ServerMessage::ResetPlayerId(_) |
@@ -513,8 +780,20 @@ impl RoomClient {
         ServerMessage::PlayerDisconnected(_) |
         ServerMessage::UnregisteredClientDisconnected |
         ServerMessage::ItemQueue(_) |
-        ServerMessage::GetItem(_) => 0, // changes room state
+        ServerMessage::GetItem(_) |
+        ServerMessage::PlayerLatency(_, _) => 0, // changes room state
         ServerMessage::PlayerName(_, _) => 1, // sets a player name and changes room state
+        ServerMessage::NewRoom(_, _) => 2, // adds a room to the lobby
+        ServerMessage::DeleteRoom(_) => 3, // removes a room from the lobby
+        ServerMessage::PrepareRestart(_) => 4, // warns about an upcoming server restart
+        ServerMessage::UpdateRoom(_, _) => 5, // changes the metadata of a room in the lobby
+        ServerMessage::ResumptionToken(_) => 6, // sets the token for reclaiming our world after a disconnect
+        ServerMessage::Ping => unreachable!(), // answered in room_client_try_recv_message
+        ServerMessage::ItemSent { .. } => 7, // reports an item sent between players (only sent to spectators)
+        ServerMessage::OwnerToken(_) => 8, // grants owner rights and sets the token for regaining them after a disconnect
+        ServerMessage::ChatMessage(_) => 9, // adds a message to the room's text chat
+        ServerMessage::LeftRoom(_) => unreachable!(), // received in room_client_leave
+        ServerMessage::Warning(_) => 10, // shows a non-fatal error
     }
 }
 
@@ -531,14 +810,25 @@ impl RoomClient {
         ServerMessage::PlayerId(world) |
         ServerMessage::ResetPlayerId(world) |
         ServerMessage::PlayerDisconnected(world) |
-        ServerMessage::PlayerName(world, _) => world.get(),
+        ServerMessage::PlayerName(world, _) |
+        ServerMessage::PlayerLatency(world, _) => world.get(),
         ServerMessage::Error(_) |
-        ServerMessage::NewRoom(_) |
+        ServerMessage::NewRoom(_, _) |
         ServerMessage::EnterRoom { .. } |
         ServerMessage::ClientConnected |
         ServerMessage::UnregisteredClientDisconnected |
         ServerMessage::ItemQueue(_) |
-        ServerMessage::GetItem(_) => panic!("this message variant has no world ID"),
+        ServerMessage::GetItem(_) |
+        ServerMessage::DeleteRoom(_) |
+        ServerMessage::PrepareRestart(_) |
+        ServerMessage::UpdateRoom(_, _) |
+        ServerMessage::ResumptionToken(_) |
+        ServerMessage::Ping |
+        ServerMessage::ItemSent { .. } |
+        ServerMessage::OwnerToken(_) |
+        ServerMessage::ChatMessage(_) |
+        ServerMessage::LeftRoom(_) |
+        ServerMessage::Warning(_) => panic!("this message variant has no world ID"),
     }
 }
 
@@ -558,38 +848,203 @@ impl RoomClient {
     }
 }
 
+/// # Safety
+///
+/// `msg` must point at a valid `ServerMessage`.
+///
+/// # Panics
+///
+/// If the `ServerMessage` variant doesn't contain a room name.
+#[no_mangle] pub unsafe extern "C" fn message_room_name(msg: *const ServerMessage) -> StringHandle {
+    let msg = &*msg;
+    match msg {
+        ServerMessage::NewRoom(name, _) |
+        ServerMessage::DeleteRoom(name) |
+        ServerMessage::UpdateRoom(name, _) => StringHandle::from_string(name),
+        _ => panic!("this message variant has no room name"),
+    }
+}
+
+/// # Safety
+///
+/// `msg` must point at a valid `ServerMessage`.
+///
+/// # Panics
+///
+/// If the `ServerMessage` variant isn't `Warning`.
+#[no_mangle] pub unsafe extern "C" fn message_warning(msg: *const ServerMessage) -> StringHandle {
+    let msg = &*msg;
+    if let ServerMessage::Warning(warning) = msg {
+        StringHandle::from_string(warning)
+    } else {
+        panic!("this message variant has no warning")
+    }
+}
+
+/// Returns the time of an announced server restart as a Unix timestamp.
+///
+/// # Safety
+///
+/// `msg` must point at a valid `ServerMessage`.
+///
+/// # Panics
+///
+/// If the `ServerMessage` variant isn't `PrepareRestart`.
+#[no_mangle] pub unsafe extern "C" fn message_restart_eta(msg: *const ServerMessage) -> i64 {
+    let msg = &*msg;
+    if let ServerMessage::PrepareRestart(eta) = msg {
+        eta.timestamp()
+    } else {
+        panic!("this message variant has no restart time")
+    }
+}
+
 /// # Safety
 ///
 /// `room_client` must point at a valid `RoomClient`, and `msg` must point at a valid `ServerMessage`. This function takes ownership of the `ServerMessage`.
 #[no_mangle] pub unsafe extern "C" fn room_client_apply_message(room_client: *mut RoomClient, msg: HandleOwned<ServerMessage>) {
     let room_client = &mut *room_client;
     match *msg.into_box() {
-        ServerMessage::Error(_) | ServerMessage::NewRoom(_) => unreachable!(),
-        ServerMessage::EnterRoom { players, num_unassigned_clients } => {
+        ServerMessage::Error(_) | ServerMessage::NewRoom(_, _) | ServerMessage::DeleteRoom(_) | ServerMessage::UpdateRoom(_, _) | ServerMessage::Ping | ServerMessage::LeftRoom(_) => unreachable!(),
+        ServerMessage::EnterRoom { players, num_unassigned_clients, chat_history } => {
             room_client.players = players;
             room_client.num_unassigned_clients = num_unassigned_clients;
+            room_client.chat_history = chat_history;
         }
         ServerMessage::PlayerId(world) => if let Err(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
             room_client.players.insert(idx, Player::new(world));
             room_client.num_unassigned_clients -= 1;
         },
-        ServerMessage::ResetPlayerId(world) => if let Ok(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
-            room_client.players.remove(idx);
-            room_client.num_unassigned_clients += 1;
-        },
+        ServerMessage::ResetPlayerId(world) => {
+            if let Ok(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
+                room_client.players.remove(idx);
+                room_client.num_unassigned_clients += 1;
+            }
+            room_client.latencies.remove(&world);
+        }
         ServerMessage::ClientConnected => room_client.num_unassigned_clients += 1,
-        ServerMessage::PlayerDisconnected(world) => if let Ok(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
-            room_client.players.remove(idx);
-        },
+        ServerMessage::PlayerDisconnected(world) => {
+            if let Ok(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
+                room_client.players.remove(idx);
+            }
+            room_client.latencies.remove(&world);
+        }
         ServerMessage::UnregisteredClientDisconnected => room_client.num_unassigned_clients -= 1,
         ServerMessage::PlayerName(world, name) => if let Ok(idx) = room_client.players.binary_search_by_key(&world, |p| p.world) {
             room_client.players[idx].name = name;
         },
         ServerMessage::ItemQueue(queue) => room_client.item_queue = queue,
         ServerMessage::GetItem(item) => room_client.item_queue.push(item),
+        ServerMessage::PrepareRestart(_) | ServerMessage::ItemSent { .. } | ServerMessage::Warning(_) => {}
+        ServerMessage::ResumptionToken(token) => room_client.resumption_token = Some(token),
+        ServerMessage::OwnerToken(token) => room_client.owner_token = Some(token),
+        ServerMessage::PlayerLatency(world, rtt) => { room_client.latencies.insert(world, rtt); }
+        ServerMessage::ChatMessage(msg) => {
+            if room_client.chat_history.len() >= CHAT_HISTORY_LEN {
+                room_client.chat_history.remove(0);
+            }
+            room_client.chat_history.push(msg);
+        }
     }
 }
 
+/// Returns the room's recent chat messages, one per line, oldest first.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`.
+#[no_mangle] pub unsafe extern "C" fn room_client_format_chat(room_client: *const RoomClient) -> StringHandle {
+    let room_client = &*room_client;
+    StringHandle::from_string(room_client.chat_history.iter().map(format_chat_message).collect::<Vec<_>>().join("\r\n"))
+}
+
+/// Returns an error without sending anything if the server would reject the message, e.g. because it's too long.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`. `text` must be a null-terminated UTF-8 string.
+#[no_mangle] pub unsafe extern "C" fn room_client_send_chat_message(room_client: *mut RoomClient, text: *const c_char) -> HandleOwned<DebugResult<()>> {
+    let room_client = &mut *room_client;
+    let text = CStr::from_ptr(text).to_str().expect("chat message was not valid UTF-8").to_owned();
+    HandleOwned::new(if let Some(e) = chat_message_error(&text) {
+        Err(DebugError(e))
+    } else {
+        room_client.write(&RoomClientMessage::ChatMessage(text)).map_err(DebugError::from)
+    })
+}
+
+/// Leaves the room and returns to the lobby. Blocks until the server has sent the current room list.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`. This function takes ownership of the `RoomClient`.
+#[no_mangle] pub unsafe extern "C" fn room_client_leave(room_client: HandleOwned<RoomClient>) -> HandleOwned<DebugResult<LobbyClient>> {
+    let mut room_client = room_client.into_box();
+    HandleOwned::new(room_client.write(&RoomClientMessage::LeaveRoom)
+        .map_err(DebugError::from)
+        .and_then(|()| if room_client.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(DebugError("residual data in room client buffer upon leaving room".to_owned())) //TODO add blocking read with buffer prefix to async-proto?
+        })
+        .and_then(|()| {
+            room_client.stream.sock().set_nonblocking(false)?;
+            loop {
+                break match ServerMessage::read_sync(&mut room_client.stream) {
+                    Ok(ServerMessage::Error(e)) => Err(DebugError(e)),
+                    Ok(ServerMessage::LeftRoom(rooms)) => Ok(rooms),
+                    // messages about the room which were sent before the server processed our request
+                    Ok(_) => continue,
+                    Err(e) => Err(DebugError::from(e)),
+                }
+            }
+        })
+        .map(|rooms| LobbyClient {
+            rooms,
+            stream: room_client.stream,
+            server_addr: room_client.server_addr,
+            buf: Vec::default(),
+        }))
+}
+
+/// Like `lobby_client_server_addr`, for use while in a room.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`.
+#[no_mangle] pub unsafe extern "C" fn room_client_server_addr(room_client: *const RoomClient) -> StringHandle {
+    StringHandle::from_string((&*room_client).server_addr)
+}
+
+/// Returns a pointer to the 16-byte resumption token for the world this client has claimed, or null if the server hasn't sent one.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`.
+#[no_mangle] pub unsafe extern "C" fn room_client_resumption_token(room_client: *const RoomClient) -> *const u8 {
+    let room_client = &*room_client;
+    room_client.resumption_token.as_ref().map_or(ptr::null(), |token| &token[0])
+}
+
+/// Returns a pointer to the 16-byte token for regaining owner rights in this room, or null if we're not an owner.
+///
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`.
+#[no_mangle] pub unsafe extern "C" fn room_client_owner_token(room_client: *const RoomClient) -> *const u8 {
+    let room_client = &*room_client;
+    room_client.owner_token.as_ref().map_or(ptr::null(), |token| &token[0])
+}
+
+/// # Safety
+///
+/// `room_client` must point at a valid `RoomClient`. `token` must point at 16 bytes previously returned by `room_client_owner_token`.
+#[no_mangle] pub unsafe extern "C" fn room_client_claim_ownership(room_client: *mut RoomClient, token: *const u8) -> HandleOwned<DebugResult<()>> {
+    let room_client = &mut *room_client;
+    let token = slice::from_raw_parts(token, 16).try_into().expect("owner tokens are 16 bytes");
+    HandleOwned::new(room_client.write(&RoomClientMessage::ClaimOwnership(token)).map_err(DebugError::from))
+}
+
 /// # Safety
 ///
 /// `room_client` must point at a valid `RoomClient`.
@@ -5,12 +5,27 @@
 
 use {
     std::{
-        collections::BTreeSet,
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
+        fmt,
         future::Future,
-        num::NonZeroU8,
+        mem,
+        net::{
+            IpAddr,
+            Ipv4Addr,
+            SocketAddr,
+        },
+        num::{
+            NonZeroU8,
+            ParseIntError,
+        },
         sync::Arc,
+        time::Duration,
     },
     async_proto::Protocol as _,
+    chrono::prelude::*,
     iced::{
         Command,
         Settings,
@@ -24,14 +39,26 @@ use {
     },
     itertools::Itertools as _,
     tokio::{
+        io::AsyncWrite,
         net::tcp::OwnedWriteHalf,
         sync::Mutex,
     },
     multiworld::{
+        CHAT_HISTORY_LEN,
+        ChatMessage,
         LobbyClientMessage,
         Player,
         RoomClientMessage,
+        RoomInfo,
         ServerMessage,
+        chat_message_error,
+        config::Config,
+        discovery::{
+            LanServers,
+            format_lan_server,
+        },
+        format_chat_message,
+        format_room_info,
         format_room_state,
     },
 };
@@ -40,10 +67,19 @@ mod subscriptions;
 
 const MW_PJ64_PROTO_VERSION: u8 = 0; //TODO sync with JS code
 
+/// The write half of a connection to either the public server (over TLS if the server supports it) or a LAN server (unencrypted).
+trait ServerWrite: AsyncWrite + fmt::Debug + Unpin + Send {}
+
+impl<T: AsyncWrite + fmt::Debug + Unpin + Send> ServerWrite for T {}
+
+type ServerWriter = Box<dyn ServerWrite>;
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum Error {
     #[error(transparent)] Client(#[from] multiworld::ClientError),
+    #[error(transparent)] Config(#[from] multiworld::config::Error),
     #[error(transparent)] Io(#[from] tokio::io::Error),
+    #[error(transparent)] Migrate(#[from] sqlx::migrate::MigrateError),
     #[error(transparent)] Read(#[from] async_proto::ReadError),
     #[error(transparent)] Write(#[from] async_proto::WriteError),
     #[error("server error: {0}")]
@@ -54,19 +90,41 @@ pub(crate) enum Error {
 
 #[derive(Debug, Clone)]
 enum Message {
+    ChangeRoomPassword,
     CommandError(Arc<Error>),
+    ConnectLan,
+    ConnectDefault,
+    ConnectLanServer(IpAddr),
+    HostLanServer,
     JoinRoom,
+    KickPlayer(NonZeroU8),
+    LanDiscoveryError(Arc<Error>),
+    LanServerError(Arc<Error>),
+    LanServerStarted,
+    LanServers(LanServers),
+    LeaveRoom,
     Nop,
     Pj64Connected(Arc<Mutex<OwnedWriteHalf>>),
     Pj64SubscriptionError(Arc<Error>),
     Plugin(subscriptions::ClientMessage),
-    Rooms(Arc<Mutex<OwnedWriteHalf>>, BTreeSet<String>),
+    Reconnect,
+    Rooms(Arc<Mutex<ServerWriter>>, SocketAddr, BTreeMap<String, RoomInfo>),
+    SaveServer,
+    SendChat,
     Server(ServerMessage),
     ServerSubscriptionError(Arc<Error>),
+    SetChatInput(String),
     SetCreateNewRoom(bool),
     SetExistingRoomSelection(String),
+    SetLanAddress(String),
     SetNewRoomName(String),
+    SetNewRoomPassword(String),
     SetPassword(String),
+    SetPortInput(String),
+    SetRoomLocked(bool),
+    SetServerInput(String),
+    SetSpoilerLogPath(String),
+    UnassignWorld(NonZeroU8),
 }
 
 fn cmd(future: impl Future<Output = Result<Message, Error>> + Send + 'static) -> Command<Message> {
@@ -78,38 +136,216 @@ fn cmd(future: impl Future<Output = Result<Message, Error>> + Send + 'static) ->
     })))
 }
 
+/// An entry in the room picker.
+#[derive(Clone, PartialEq, Eq)]
+struct RoomOption {
+    name: String,
+    info: RoomInfo,
+}
+
+impl fmt::Display for RoomOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, format_room_info(&self.info))
+    }
+}
+
 enum ServerConnectionState {
     Error(Arc<Error>),
     Init,
     Lobby {
-        rooms: BTreeSet<String>,
+        rooms: BTreeMap<String, RoomInfo>,
         create_new_room: bool,
         existing_room_selection: Option<String>,
         new_room_name: String,
         password: String,
+        spoiler_log_path: String,
     },
     Room {
         players: Vec<Player>,
         num_unassigned_clients: u8,
+        latencies: HashMap<NonZeroU8, Duration>,
+        /// Whether we have owner rights, which enables the moderation controls.
+        owner: bool,
+        new_room_password: String,
+        /// Recent messages from the room's text chat, oldest first.
+        chat: Vec<ChatMessage>,
+        chat_input: String,
+        /// The most recent [`ServerMessage::Warning`], if any.
+        warning: Option<String>,
     },
 }
 
+impl ServerConnectionState {
+    fn lobby(rooms: BTreeMap<String, RoomInfo>) -> Self {
+        Self::Lobby {
+            create_new_room: rooms.is_empty(),
+            existing_room_selection: None,
+            new_room_name: String::default(),
+            spoiler_log_path: String::default(),
+            password: String::default(),
+            rooms,
+        }
+    }
+}
+
 struct State {
     command_error: Option<Arc<Error>>,
     pj64_subscription_error: Option<Arc<Error>>,
     pj64_writer: Option<Arc<Mutex<OwnedWriteHalf>>>,
     server_connection: ServerConnectionState,
-    server_writer: Option<Arc<Mutex<OwnedWriteHalf>>>,
+    server_writer: Option<Arc<Mutex<ServerWriter>>>,
     player_id: Option<NonZeroU8>,
     player_name: Option<[u8; 8]>,
+    server_restart: Option<DateTime<Utc>>,
+    /// Name of the room we're in or trying to join.
+    room_name: Option<String>,
+    /// The room and token to use for reclaiming our world after a disconnect.
+    resumption: Option<(String, [u8; 16])>,
+    /// The room and token to use for regaining owner rights after a reconnect.
+    owner_token: Option<(String, [u8; 16])>,
+    /// Incremented to restart the server connection subscription.
+    server_connection_id: u64,
+    /// The address we're connected to, which shows whether IPv6 or IPv4 was used.
+    server_addr: Option<SocketAddr>,
+    config: Config,
+    config_error: Option<Arc<Error>>,
+    server_input: String,
+    port_input: String,
+    /// The LAN server we're connected to, or `None` if we're using the server from the config.
+    lan_address: Option<IpAddr>,
+    lan_address_input: String,
+    /// Whether we're running a LAN server for other players to connect to.
+    hosting_lan: bool,
+    lan_server_error: Option<Arc<Error>>,
+    /// Servers found on the local network by the most recent discovery query.
+    lan_servers: LanServers,
+    lan_discovery_error: Option<Arc<Error>>,
+}
+
+impl State {
+    fn restart_warning(&self) -> Column<'_, Message> {
+        let mut col = Column::new();
+        if let Some(eta) = self.server_restart {
+            col = col.push(Text::new(format!("The server is going to restart at {}. You will need to reconnect afterwards.", eta.with_timezone(&Local).format("%H:%M"))));
+        }
+        col
+    }
+
+    fn reconnect(&mut self) {
+        self.server_connection = ServerConnectionState::Init;
+        self.server_writer = None;
+        self.server_addr = None;
+        self.server_restart = None;
+        self.server_connection_id += 1;
+    }
+
+    /// Switches to a different server. Tokens from the previous server aren't valid there, so they're discarded.
+    fn connect_to(&mut self, lan_address: Option<IpAddr>) {
+        self.lan_address = lan_address;
+        self.resumption = None;
+        self.owner_token = None;
+        self.room_name = None;
+        self.reconnect();
+    }
+
+    fn lan_controls(&self) -> Column<'_, Message> {
+        let mut col = Column::new();
+        if let Some(ref e) = self.lan_discovery_error {
+            col = col.push(Text::new(format!("Failed to look for LAN servers: {e}")));
+        } else if self.lan_servers.is_empty() {
+            col = col.push(Text::new("No servers found on the local network."));
+        } else {
+            col = col.push(Text::new("Servers on the local network:"));
+            for (&addr, rooms) in &self.lan_servers {
+                col = col.push(Row::new()
+                    .push(Text::new(format_lan_server(addr, rooms)))
+                    .push({
+                        let mut btn = Button::new(Text::new("Connect"));
+                        if self.lan_address != Some(addr) { btn = btn.on_press(Message::ConnectLanServer(addr)) }
+                        btn
+                    })
+                    .spacing(8)
+                );
+            }
+        }
+        col = col
+            .push(Row::new()
+                .push(TextInput::new("LAN server IP address", &self.lan_address_input, Message::SetLanAddress).on_submit(Message::ConnectLan).padding(5))
+                .push({
+                    let mut btn = Button::new(Text::new("Connect"));
+                    if self.lan_address_input.parse::<IpAddr>().is_ok() { btn = btn.on_press(Message::ConnectLan) }
+                    btn
+                })
+                .spacing(8)
+            );
+        if self.hosting_lan {
+            col = col.push(Text::new("Hosting a LAN server. Other players can connect to it using this computer's local IP address."));
+        } else {
+            if let Some(ref e) = self.lan_server_error {
+                col = col.push(Text::new(format!("Failed to host LAN server: {e}")));
+            }
+            col = col.push(Button::new(Text::new("Host LAN server")).on_press(Message::HostLanServer));
+        }
+        if self.lan_address.is_some() {
+            col = col.push(Button::new(Text::new(if self.config.server.is_some() { "Connect to configured server" } else { "Connect to public server" })).on_press(Message::ConnectDefault));
+        }
+        col.spacing(8)
+    }
+
+    /// The port entered in the server settings. An empty input means the default port.
+    fn parsed_port(&self) -> Result<Option<u16>, ParseIntError> {
+        if self.port_input.is_empty() {
+            Ok(None)
+        } else {
+            self.port_input.parse().map(Some)
+        }
+    }
+
+    fn server_controls(&self) -> Column<'_, Message> {
+        let mut col = Column::new();
+        if let Some(addr) = self.server_addr {
+            col = col.push(Text::new(format!("Connected to {addr}")));
+        }
+        if let Some(ref e) = self.config_error {
+            col = col.push(Text::new(format!("Failed to load settings, using defaults: {e}")));
+        }
+        col
+            .push(Row::new()
+                .push(TextInput::new("Server (empty for public server)", &self.server_input, Message::SetServerInput).on_submit(Message::SaveServer).padding(5))
+                .push(TextInput::new("Port", &self.port_input, Message::SetPortInput).on_submit(Message::SaveServer).padding(5).width(iced::Length::Units(64)))
+                .push({
+                    let mut btn = Button::new(Text::new("Save"));
+                    if self.parsed_port().is_ok() { btn = btn.on_press(Message::SaveServer) }
+                    btn
+                })
+                .spacing(8)
+            )
+            .spacing(8)
+    }
+
+    fn send_room_message(&self, msg: RoomClientMessage) -> Command<Message> {
+        if let Some(ref writer) = self.server_writer {
+            let writer = writer.clone();
+            cmd(async move {
+                msg.write(&mut *writer.lock().await).await?;
+                Ok(Message::Nop)
+            })
+        } else {
+            Command::none()
+        }
+    }
 }
 
 impl Application for State {
     type Executor = iced::executor::Default;
     type Message = Message;
-    type Flags = ();
+    type Flags = Result<Config, multiworld::config::Error>;
 
-    fn new((): ()) -> (Self, Command<Message>) {
+    fn new(config: Result<Config, multiworld::config::Error>) -> (Self, Command<Message>) {
+        let (config, config_error) = match config {
+            Ok(config) => (config, None),
+            Err(e) => (Config::default(), Some(Arc::new(e.into()))),
+        };
         (Self {
             command_error: None,
             pj64_subscription_error: None,
@@ -118,6 +354,21 @@ impl Application for State {
             server_writer: None,
             player_id: None,
             player_name: None,
+            server_restart: None,
+            room_name: None,
+            resumption: None,
+            owner_token: None,
+            server_connection_id: 0,
+            server_addr: None,
+            server_input: config.server.clone().unwrap_or_default(),
+            port_input: config.port.map(|port| port.to_string()).unwrap_or_default(),
+            config, config_error,
+            lan_address: None,
+            lan_address_input: String::default(),
+            hosting_lan: false,
+            lan_server_error: None,
+            lan_servers: LanServers::default(),
+            lan_discovery_error: None,
         }, Command::none())
     }
 
@@ -125,27 +376,55 @@ impl Application for State {
 
     fn update(&mut self, msg: Message) -> Command<Message> {
         match msg {
+            Message::ChangeRoomPassword => if let ServerConnectionState::Room { ref mut new_room_password, .. } = self.server_connection {
+                let password = mem::take(new_room_password);
+                return self.send_room_message(RoomClientMessage::SetPassword(password))
+            },
             Message::CommandError(e) => { self.command_error.get_or_insert(e); }
-            Message::JoinRoom => if let ServerConnectionState::Lobby { create_new_room, ref existing_room_selection, ref new_room_name, ref password, .. } = self.server_connection {
-                if !password.is_empty() {
+            Message::ConnectLan => if let Ok(lan_address) = self.lan_address_input.parse() {
+                self.connect_to(Some(lan_address));
+            },
+            Message::ConnectDefault => self.connect_to(None),
+            Message::ConnectLanServer(addr) => self.connect_to(Some(addr)),
+            Message::HostLanServer => {
+                self.hosting_lan = true;
+                self.lan_server_error = None;
+            }
+            Message::JoinRoom => if let ServerConnectionState::Lobby { ref rooms, create_new_room, ref existing_room_selection, ref new_room_name, ref password, ref spoiler_log_path } = self.server_connection {
+                if create_new_room || existing_room_selection.as_ref().and_then(|name| rooms.get(name)).map_or(false, |info| !info.locked && (!info.password_protected || !password.is_empty())) {
                     let existing_room_selection = existing_room_selection.clone();
                     let new_room_name = new_room_name.clone();
                     let password = password.clone();
+                    let spoiler_log_path = spoiler_log_path.clone();
                     let writer = self.server_writer.clone().expect("join room button only appears when connected to server");
+                    self.room_name = if create_new_room { Some(new_room_name.clone()) } else { existing_room_selection.clone() };
                     return cmd(async move {
+                        let spoiler_log = if spoiler_log_path.is_empty() { None } else { Some(tokio::fs::read_to_string(spoiler_log_path).await?) };
                         if create_new_room {
                             if !new_room_name.is_empty() {
-                                LobbyClientMessage::CreateRoom { name: new_room_name, password }.write(&mut *writer.lock().await).await?;
+                                LobbyClientMessage::CreateRoom { name: new_room_name, password, spoiler_log }.write(&mut *writer.lock().await).await?;
                             }
                         } else {
                             if let Some(name) = existing_room_selection {
-                                LobbyClientMessage::JoinRoom { name, password }.write(&mut *writer.lock().await).await?;
+                                LobbyClientMessage::JoinRoom { name, password, spoiler_log }.write(&mut *writer.lock().await).await?;
                             }
                         }
                         Ok(Message::Nop)
                     })
                 }
             }
+            Message::KickPlayer(world) => return self.send_room_message(RoomClientMessage::KickPlayer(world)),
+            Message::LanDiscoveryError(e) => self.lan_discovery_error = Some(e),
+            Message::LanServerError(e) => {
+                self.hosting_lan = false;
+                self.lan_server_error = Some(e);
+            }
+            Message::LanServerStarted => self.connect_to(Some(IpAddr::V4(Ipv4Addr::LOCALHOST))),
+            Message::LanServers(servers) => {
+                self.lan_servers = servers;
+                self.lan_discovery_error = None;
+            }
+            Message::LeaveRoom => return self.send_room_message(RoomClientMessage::LeaveRoom),
             Message::Nop => {}
             Message::Pj64Connected(writer) => self.pj64_writer = Some(writer),
             Message::Pj64SubscriptionError(e) => { self.pj64_subscription_error.get_or_insert(e); }
@@ -185,27 +464,60 @@ impl Application for State {
                     Ok(Message::Nop)
                 })
             }
-            Message::Rooms(writer, rooms) => {
-                self.server_writer = Some(writer);
-                self.server_connection = ServerConnectionState::Lobby {
-                    create_new_room: rooms.is_empty(),
-                    existing_room_selection: None,
-                    new_room_name: String::default(),
-                    password: String::default(),
-                    rooms,
-                };
+            Message::Reconnect => self.reconnect(),
+            Message::Rooms(writer, addr, rooms) => {
+                self.server_writer = Some(writer.clone());
+                self.server_addr = Some(addr);
+                if let Some((room, token)) = self.resumption.clone() {
+                    return cmd(async move {
+                        LobbyClientMessage::Resume { room, token }.write(&mut *writer.lock().await).await?;
+                        Ok(Message::Nop)
+                    })
+                }
+                self.server_connection = ServerConnectionState::lobby(rooms);
             }
+            Message::SaveServer => if let Ok(port) = self.parsed_port() {
+                let server = self.server_input.trim();
+                self.config.server = (!server.is_empty()).then(|| server.to_owned());
+                self.config.port = port;
+                self.connect_to(None);
+                let config = self.config.clone();
+                return cmd(async move {
+                    config.save().await?;
+                    Ok(Message::Nop)
+                })
+            },
+            Message::SendChat => if let ServerConnectionState::Room { ref mut chat_input, .. } = self.server_connection {
+                if chat_message_error(chat_input).is_none() {
+                    let text = mem::take(chat_input);
+                    return self.send_room_message(RoomClientMessage::ChatMessage(text))
+                }
+            },
             Message::Server(ServerMessage::Error(e)) => if !matches!(self.server_connection, ServerConnectionState::Error(_)) {
+                if let ServerConnectionState::Init = self.server_connection {
+                    // failed to resume, so don't offer to try again
+                    self.resumption = None;
+                }
                 self.server_connection = ServerConnectionState::Error(Arc::new(Error::Server(e)));
             },
-            Message::Server(ServerMessage::NewRoom(name)) => if let ServerConnectionState::Lobby { ref mut rooms, .. } = self.server_connection { rooms.insert(name); },
-            Message::Server(ServerMessage::EnterRoom { players, num_unassigned_clients }) => {
-                self.server_connection = ServerConnectionState::Room { players: players.clone(), num_unassigned_clients };
+            Message::Server(ServerMessage::NewRoom(name, info) | ServerMessage::UpdateRoom(name, info)) => if let ServerConnectionState::Lobby { ref mut rooms, .. } = self.server_connection { rooms.insert(name, info); },
+            Message::Server(ServerMessage::DeleteRoom(name)) => if let ServerConnectionState::Lobby { ref mut rooms, ref mut existing_room_selection, .. } = self.server_connection {
+                rooms.remove(&name);
+                if existing_room_selection.as_ref() == Some(&name) {
+                    *existing_room_selection = None;
+                }
+            },
+            Message::Server(ServerMessage::EnterRoom { players, num_unassigned_clients, chat_history }) => {
+                self.server_connection = ServerConnectionState::Room { players: players.clone(), num_unassigned_clients, latencies: HashMap::default(), owner: false, new_room_password: String::default(), chat: chat_history, chat_input: String::default(), warning: None };
                 let server_writer = self.server_writer.clone().expect("join room button only appears when connected to server");
                 let pj64_writer = self.pj64_writer.clone().expect("join room button only appears when connected to server");
                 let player_id = self.player_id;
                 let player_name = self.player_name;
+                let owner_token = self.owner_token.as_ref().filter(|(room, _)| self.room_name.as_ref() == Some(room)).map(|&(_, token)| token);
                 return cmd(async move {
+                    if let Some(owner_token) = owner_token {
+                        RoomClientMessage::ClaimOwnership(owner_token).write(&mut *server_writer.lock().await).await?;
+                    }
                     if let Some(player_id) = player_id {
                         RoomClientMessage::PlayerId(player_id).write(&mut *server_writer.lock().await).await?;
                         if let Some(player_name) = player_name {
@@ -226,17 +538,19 @@ impl Application for State {
                     *num_unassigned_clients -= 1;
                 }
             },
-            Message::Server(ServerMessage::ResetPlayerId(world)) => if let ServerConnectionState::Room { ref mut players, ref mut num_unassigned_clients, .. } = self.server_connection {
+            Message::Server(ServerMessage::ResetPlayerId(world)) => if let ServerConnectionState::Room { ref mut players, ref mut num_unassigned_clients, ref mut latencies, .. } = self.server_connection {
                 if let Ok(idx) = players.binary_search_by_key(&world, |p| p.world) {
                     players.remove(idx);
                     *num_unassigned_clients += 1;
                 }
+                latencies.remove(&world);
             },
             Message::Server(ServerMessage::ClientConnected) => if let ServerConnectionState::Room { ref mut num_unassigned_clients, .. } = self.server_connection { *num_unassigned_clients += 1 },
-            Message::Server(ServerMessage::PlayerDisconnected(world)) => if let ServerConnectionState::Room { ref mut players, .. } = self.server_connection {
+            Message::Server(ServerMessage::PlayerDisconnected(world)) => if let ServerConnectionState::Room { ref mut players, ref mut latencies, .. } = self.server_connection {
                 if let Ok(idx) = players.binary_search_by_key(&world, |p| p.world) {
                     players.remove(idx);
                 }
+                latencies.remove(&world);
             },
             Message::Server(ServerMessage::UnregisteredClientDisconnected) => if let ServerConnectionState::Room { ref mut num_unassigned_clients, .. } = self.server_connection { *num_unassigned_clients -= 1 },
             Message::Server(ServerMessage::PlayerName(world, name)) => if let ServerConnectionState::Room { ref mut players, .. } = self.server_connection {
@@ -263,13 +577,60 @@ impl Application for State {
                     Ok(Message::Nop)
                 })
             }
+            Message::Server(ServerMessage::PrepareRestart(eta)) => self.server_restart = Some(eta),
+            Message::Server(ServerMessage::ResumptionToken(token)) => if let Some(ref room_name) = self.room_name {
+                self.resumption = Some((room_name.clone(), token));
+            },
+            Message::Server(ServerMessage::Ping) => if let Some(ref writer) = self.server_writer {
+                let writer = writer.clone();
+                let in_lobby = matches!(self.server_connection, ServerConnectionState::Lobby { .. });
+                return cmd(async move {
+                    if in_lobby {
+                        LobbyClientMessage::Pong.write(&mut *writer.lock().await).await?;
+                    } else {
+                        RoomClientMessage::Pong.write(&mut *writer.lock().await).await?;
+                    }
+                    Ok(Message::Nop)
+                })
+            },
+            Message::Server(ServerMessage::PlayerLatency(world, rtt)) => if let ServerConnectionState::Room { ref mut latencies, .. } = self.server_connection {
+                latencies.insert(world, rtt);
+            },
+            Message::Server(ServerMessage::ItemSent { .. }) => {} // only sent to spectators
+            Message::Server(ServerMessage::OwnerToken(token)) => {
+                if let Some(ref room_name) = self.room_name {
+                    self.owner_token = Some((room_name.clone(), token));
+                }
+                if let ServerConnectionState::Room { ref mut owner, .. } = self.server_connection { *owner = true }
+            }
+            Message::Server(ServerMessage::ChatMessage(msg)) => if let ServerConnectionState::Room { ref mut chat, .. } = self.server_connection {
+                if chat.len() >= CHAT_HISTORY_LEN {
+                    chat.remove(0);
+                }
+                chat.push(msg);
+            },
+            Message::Server(ServerMessage::Warning(msg)) => if let ServerConnectionState::Room { ref mut warning, .. } = self.server_connection { *warning = Some(msg) },
+            Message::Server(ServerMessage::LeftRoom(rooms)) => {
+                // we left voluntarily, so there's no world to reclaim
+                self.resumption = None;
+                self.room_name = None;
+                self.server_connection = ServerConnectionState::lobby(rooms);
+            }
             Message::ServerSubscriptionError(e) => if !matches!(self.server_connection, ServerConnectionState::Error(_)) {
                 self.server_connection = ServerConnectionState::Error(e);
             },
+            Message::SetChatInput(text) => if let ServerConnectionState::Room { ref mut chat_input, .. } = self.server_connection { *chat_input = text },
             Message::SetCreateNewRoom(new_val) => if let ServerConnectionState::Lobby { ref mut create_new_room, .. } = self.server_connection { *create_new_room = new_val },
             Message::SetExistingRoomSelection(name) => if let ServerConnectionState::Lobby { ref mut existing_room_selection, .. } = self.server_connection { *existing_room_selection = Some(name) },
+            Message::SetLanAddress(address) => self.lan_address_input = address,
             Message::SetNewRoomName(name) => if let ServerConnectionState::Lobby { ref mut new_room_name, .. } = self.server_connection { *new_room_name = name },
+            Message::SetNewRoomPassword(password) => if let ServerConnectionState::Room { ref mut new_room_password, .. } = self.server_connection { *new_room_password = password },
             Message::SetPassword(new_password) => if let ServerConnectionState::Lobby { ref mut password, .. } = self.server_connection { *password = new_password },
+            Message::SetPortInput(port) => self.port_input = port,
+            Message::SetRoomLocked(locked) => return self.send_room_message(RoomClientMessage::SetLocked(locked)),
+            Message::SetServerInput(server) => self.server_input = server,
+            Message::SetSpoilerLogPath(path) => if let ServerConnectionState::Lobby { ref mut spoiler_log_path, .. } = self.server_connection { *spoiler_log_path = path },
+            Message::UnassignWorld(world) => return self.send_room_message(RoomClientMessage::UnassignWorld(world)),
         }
         Command::none()
     }
@@ -299,53 +660,133 @@ impl Application for State {
                 .into()
         } else {
             match self.server_connection {
-                ServerConnectionState::Error(ref e) => Column::new()
-                    .push(Text::new("An error occurred during communication with the server:"))
-                    .push(Text::new(e.to_string()))
-                    .push(Text::new(format!("Please report this error to Fenhl. Debug info: {e:?}")))
-                    .spacing(8)
-                    .padding(8)
-                    .into(),
+                ServerConnectionState::Error(ref e) => {
+                    let mut col = Column::new()
+                        .push(Text::new("An error occurred during communication with the server:"))
+                        .push(Text::new(e.to_string()))
+                        .push(Text::new(format!("Please report this error to Fenhl. Debug info: {e:?}")));
+                    if self.resumption.is_some() {
+                        col = col.push(Button::new(Text::new("Reconnect")).on_press(Message::Reconnect));
+                    }
+                    col
+                        .push(self.server_controls())
+                        .push(self.lan_controls())
+                        .spacing(8)
+                        .padding(8)
+                        .into()
+                }
                 ServerConnectionState::Init => Column::new()
-                    .push(Text::new("Connecting to server…"))
+                    .push(Text::new(if self.resumption.is_some() { "Reconnecting to server…" } else { "Connecting to server…" }))
                     .spacing(8)
                     .padding(8)
                     .into(),
-                ServerConnectionState::Lobby { ref rooms, create_new_room, ref existing_room_selection, ref new_room_name, ref password } => Column::new()
-                    .push(Radio::new(false, "Connect to existing room", Some(create_new_room), Message::SetCreateNewRoom))
-                    .push(Radio::new(true, "Create new room", Some(create_new_room), Message::SetCreateNewRoom))
-                    .push(if create_new_room {
-                        Element::from(TextInput::new("Room name", new_room_name, Message::SetNewRoomName).on_submit(Message::JoinRoom).padding(5))
-                    } else {
-                        if rooms.is_empty() {
-                            Text::new("(no rooms currently open)").into()
+                ServerConnectionState::Lobby { ref rooms, create_new_room, ref existing_room_selection, ref new_room_name, ref password, ref spoiler_log_path } => {
+                    let mut col = self.restart_warning()
+                        .push(Radio::new(false, "Connect to existing room", Some(create_new_room), Message::SetCreateNewRoom))
+                        .push(Radio::new(true, "Create new room", Some(create_new_room), Message::SetCreateNewRoom))
+                        .push(if create_new_room {
+                            Element::from(TextInput::new("Room name", new_room_name, Message::SetNewRoomName).on_submit(Message::JoinRoom).padding(5))
                         } else {
-                            PickList::new(rooms.iter().cloned().collect_vec(), existing_room_selection.clone(), Message::SetExistingRoomSelection).into()
+                            if rooms.is_empty() {
+                                Text::new("(no rooms currently open)").into()
+                            } else {
+                                let options = rooms.iter().map(|(name, &info)| RoomOption { name: name.clone(), info }).collect_vec();
+                                let selection = existing_room_selection.as_ref().and_then(|name| options.iter().find(|option| option.name == *name)).cloned();
+                                PickList::new(options, selection, |option| Message::SetExistingRoomSelection(option.name)).into()
+                            }
+                        })
+                        .push(TextInput::new(if create_new_room { "Password (optional)" } else { "Password" }, password, Message::SetPassword).password().on_submit(Message::JoinRoom).padding(5))
+                        .push(TextInput::new("Spoiler log path (optional)", spoiler_log_path, Message::SetSpoilerLogPath).on_submit(Message::JoinRoom).padding(5));
+                    col
+                        .push({
+                            let mut btn = Button::new(Text::new("Connect"));
+                            if if create_new_room {
+                                !new_room_name.is_empty()
+                            } else {
+                                existing_room_selection.as_ref().and_then(|name| rooms.get(name)).map_or(false, |info| !info.locked && (!info.password_protected || !password.is_empty()))
+                            } { btn = btn.on_press(Message::JoinRoom) }
+                            btn
+                        })
+                        .push(self.server_controls())
+                        .push(self.lan_controls())
+                        .spacing(8)
+                        .padding(8)
+                        .into()
+                }
+                ServerConnectionState::Room { ref players, num_unassigned_clients, ref latencies, owner, ref new_room_password, ref chat, ref chat_input, ref warning } => {
+                    let mut col = self.restart_warning()
+                        .push(Text::new(format_room_state(players, num_unassigned_clients, self.player_id, latencies)));
+                    if let Some(warning) = warning {
+                        col = col.push(Text::new(format!("Warning: {warning}")));
+                    }
+                    if owner {
+                        for player in players {
+                            col = col.push(Row::new()
+                                .push(Text::new(format!("World {}", player.world)))
+                                .push(Button::new(Text::new("Kick")).on_press(Message::KickPlayer(player.world)))
+                                .push(Button::new(Text::new("Unassign")).on_press(Message::UnassignWorld(player.world)))
+                                .spacing(8)
+                            );
                         }
-                    })
-                    .push(TextInput::new("Password", password, Message::SetPassword).password().on_submit(Message::JoinRoom).padding(5))
-                    .push({
-                        let mut btn = Button::new(Text::new("Connect"));
-                        if if create_new_room { !new_room_name.is_empty() } else { existing_room_selection.is_some() } && !password.is_empty() { btn = btn.on_press(Message::JoinRoom) }
-                        btn
-                    })
-                    .spacing(8)
-                    .padding(8)
-                    .into(),
-                ServerConnectionState::Room { ref players, num_unassigned_clients, .. } => Column::new()
-                    .push(Text::new(format_room_state(players, num_unassigned_clients, self.player_id)))
-                    .spacing(8)
-                    .padding(8)
-                    .into(),
+                        col = col
+                            .push(Row::new()
+                                .push(Button::new(Text::new("Lock room")).on_press(Message::SetRoomLocked(true)))
+                                .push(Button::new(Text::new("Unlock room")).on_press(Message::SetRoomLocked(false)))
+                                .spacing(8)
+                            )
+                            .push(Row::new()
+                                .push(TextInput::new("New password", new_room_password, Message::SetNewRoomPassword).password().on_submit(Message::ChangeRoomPassword).padding(5))
+                                .push(Button::new(Text::new("Set password")).on_press(Message::ChangeRoomPassword))
+                                .spacing(8)
+                            );
+                    }
+                    let mut chat_log = Column::new();
+                    for msg in chat {
+                        chat_log = chat_log.push(Text::new(format_chat_message(msg)));
+                    }
+                    let chat_error = chat_message_error(chat_input);
+                    let mut send_btn = Button::new(Text::new("Send"));
+                    if chat_error.is_none() { send_btn = send_btn.on_press(Message::SendChat) }
+                    col = col
+                        .push(Scrollable::new(chat_log).height(iced::Length::Units(96)))
+                        .push(Row::new()
+                            .push(TextInput::new("Chat message", chat_input, Message::SetChatInput).on_submit(Message::SendChat).padding(5))
+                            .push(send_btn)
+                            .spacing(8)
+                        );
+                    if let Some(chat_error) = chat_error.filter(|_| !chat_input.trim().is_empty()) {
+                        col = col.push(Text::new(chat_error));
+                    }
+                    col = col.push(Button::new(Text::new("Leave room")).on_press(Message::LeaveRoom));
+                    col
+                        .spacing(8)
+                        .padding(8)
+                        .into()
+                }
             }
         }
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        Subscription::batch([
+        let mut subscriptions = vec![
             Subscription::from_recipe(subscriptions::Pj64Listener),
-            Subscription::from_recipe(subscriptions::Client),
-        ])
+            Subscription::from_recipe(subscriptions::Client {
+                connection_id: self.server_connection_id,
+                custom_server: if let Some(lan_address) = self.lan_address {
+                    Some((lan_address.to_string(), multiworld::PORT))
+                } else {
+                    self.config.custom_server().map(|(host, port)| (host.to_owned(), port))
+                },
+            }),
+        ];
+        if self.hosting_lan {
+            subscriptions.push(Subscription::from_recipe(subscriptions::LanServer));
+        }
+        if let ServerConnectionState::Error(_) | ServerConnectionState::Lobby { .. } = self.server_connection {
+            // the list of LAN servers is only shown outside of rooms
+            subscriptions.push(Subscription::from_recipe(subscriptions::LanDiscovery));
+        }
+        Subscription::batch(subscriptions)
     }
 }
 
@@ -356,6 +797,6 @@ fn main() -> iced::Result {
             size: (256, 256),
             ..window::Settings::default()
         },
-        ..Settings::default()
+        ..Settings::with_flags(Config::load_sync())
     })
 }
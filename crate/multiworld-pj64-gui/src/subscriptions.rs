@@ -8,6 +8,7 @@ use {
         net::Ipv4Addr,
         num::NonZeroU8,
         sync::Arc,
+        time::Duration,
     },
     async_proto::Protocol,
     futures::{
@@ -21,19 +22,37 @@ use {
     },
     iced_futures::subscription::Recipe,
     tokio::{
+        io::{
+            self,
+            AsyncRead,
+        },
         net::{
             TcpListener,
             TcpStream,
         },
+        select,
         sync::Mutex,
+        time::interval,
+    },
+    multiworld::{
+        connect,
+        discovery,
+        server::{
+            Server,
+            accept_connections,
+            answer_discovery,
+        },
     },
     crate::{
         Error,
         MW_PJ64_PROTO_VERSION,
         Message,
+        ServerWriter,
     },
 };
 
+type ServerReader = Box<dyn AsyncRead + Unpin + Send>;
+
 #[derive(Protocol)]
 pub(crate) enum ServerMessage {
     ItemQueue(Vec<u16>),
@@ -86,28 +105,54 @@ impl<H: Hasher, I> Recipe<H, I> for Pj64Listener {
     }
 }
 
-pub(crate) struct Client;
+pub(crate) struct Client {
+    pub(crate) connection_id: u64,
+    /// The host and port of the server to connect to, or `None` to use the public server.
+    pub(crate) custom_server: Option<(String, u16)>,
+}
 
 impl<H: Hasher, I> Recipe<H, I> for Client {
     type Output = Message;
 
     fn hash(&self, state: &mut H) {
         TypeId::of::<Self>().hash(state);
+        self.connection_id.hash(state);
+        self.custom_server.hash(state);
     }
 
     fn stream(self: Box<Self>, _: BoxStream<'_, I>) -> BoxStream<'_, Message> {
-        stream::once(TcpStream::connect((multiworld::ADDRESS_V4, multiworld::PORT)))
-            .err_into::<Error>()
-            .and_then(|mut tcp_stream| async move {
+        stream::once(async move {
+            let (reader, writer, rooms, addr) = if let Some((host, port)) = self.custom_server {
+                // only the public server has a certificate, so the connection is unencrypted
+                let (mut tcp_stream, addr) = connect::tcp((&*host, port)).await?;
                 let rooms = multiworld::handshake(&mut tcp_stream).await?;
                 let (reader, writer) = tcp_stream.into_split();
-                Ok(
-                    stream::once(future::ok(Message::Rooms(Arc::new(Mutex::new(writer)), rooms)))
-                    .chain(stream::try_unfold(reader, |mut reader| async move {
-                        Ok(Some((Message::Server(multiworld::ServerMessage::read(&mut reader).await?), reader)))
-                    }))
-                )
-            })
+                (Box::new(reader) as ServerReader, Box::new(writer) as ServerWriter, rooms, addr)
+            } else {
+                let (tcp_stream, addr) = connect::tcp(&connect::public_server_addrs()[..]).await?;
+                match multiworld::connect_tls(tcp_stream).await {
+                    Ok(mut tls_stream) => {
+                        let rooms = multiworld::handshake(&mut tls_stream).await?;
+                        let (reader, writer) = io::split(tls_stream);
+                        (Box::new(reader) as ServerReader, Box::new(writer) as ServerWriter, rooms, addr)
+                    }
+                    Err(e) if multiworld::tls_unsupported(&e) => {
+                        // the server doesn't have TLS set up yet, so reconnect without encryption
+                        let mut tcp_stream = TcpStream::connect(addr).await?;
+                        let rooms = multiworld::handshake(&mut tcp_stream).await?;
+                        let (reader, writer) = tcp_stream.into_split();
+                        (Box::new(reader) as ServerReader, Box::new(writer) as ServerWriter, rooms, addr)
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            Ok::<_, Error>(
+                stream::once(future::ok(Message::Rooms(Arc::new(Mutex::new(writer)), addr, rooms)))
+                .chain(stream::try_unfold(reader, |mut reader| async move {
+                    Ok(Some((Message::Server(multiworld::ServerMessage::read(&mut reader).await?), reader)))
+                }))
+            )
+        })
             .try_flatten()
             .map(|res| match res {
                 Ok(msg) => msg,
@@ -116,3 +161,60 @@ impl<H: Hasher, I> Recipe<H, I> for Client {
             .boxed()
     }
 }
+
+/// Runs a server on this computer which other players on the local network can connect to.
+pub(crate) struct LanServer;
+
+impl<H: Hasher, I> Recipe<H, I> for LanServer {
+    type Output = Message;
+
+    fn hash(&self, state: &mut H) {
+        TypeId::of::<Self>().hash(state);
+    }
+
+    fn stream(self: Box<Self>, _: BoxStream<'_, I>) -> BoxStream<'_, Message> {
+        stream::once(async {
+            // bind to IPv4 since that's what LAN setups typically use, and IPv6 sockets on Windows don't accept IPv4 connections
+            let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, multiworld::PORT)).await?;
+            let server = Server::lan().await?;
+            Ok::<_, Error>(
+                stream::once(future::ok(Message::LanServerStarted))
+                .chain(stream::once(async move {
+                    let Err(e) = select! {
+                        res = accept_connections(listener, server.clone(), None, false) => res,
+                        res = answer_discovery(server) => res,
+                    };
+                    Err(e.into())
+                }))
+            )
+        })
+            .try_flatten()
+            .map(|res| match res {
+                Ok(msg) => msg,
+                Err(e) => Message::LanServerError(Arc::new(e)),
+            })
+            .boxed()
+    }
+}
+
+/// Periodically looks for servers on the local network.
+pub(crate) struct LanDiscovery;
+
+impl<H: Hasher, I> Recipe<H, I> for LanDiscovery {
+    type Output = Message;
+
+    fn hash(&self, state: &mut H) {
+        TypeId::of::<Self>().hash(state);
+    }
+
+    fn stream(self: Box<Self>, _: BoxStream<'_, I>) -> BoxStream<'_, Message> {
+        stream::unfold(interval(Duration::from_secs(5)), |mut interval| async move {
+            interval.tick().await;
+            let msg = match discovery::discover().await {
+                Ok(servers) => Message::LanServers(servers),
+                Err(e) => Message::LanDiscoveryError(Arc::new(e.into())),
+            };
+            Some((msg, interval))
+        }).boxed()
+    }
+}
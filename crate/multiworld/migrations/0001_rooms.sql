@@ -0,0 +1,6 @@
+CREATE TABLE rooms (
+    name TEXT NOT NULL PRIMARY KEY,
+    password TEXT NOT NULL,
+    base_queue BLOB NOT NULL,
+    player_queues BLOB NOT NULL
+);
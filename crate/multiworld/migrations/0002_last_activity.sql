@@ -0,0 +1,2 @@
+ALTER TABLE rooms ADD COLUMN last_activity TEXT NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE rooms SET last_activity = datetime('now');
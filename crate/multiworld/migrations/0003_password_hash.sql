@@ -0,0 +1,12 @@
+-- Plaintext passwords are hashed by ootrmwd on startup and then cleared, so the old column becomes nullable.
+CREATE TABLE rooms_new (
+    name TEXT NOT NULL PRIMARY KEY,
+    password TEXT,
+    password_hash TEXT,
+    base_queue BLOB NOT NULL,
+    player_queues BLOB NOT NULL,
+    last_activity TEXT NOT NULL
+);
+INSERT INTO rooms_new (name, password, base_queue, player_queues, last_activity) SELECT name, password, base_queue, player_queues, last_activity FROM rooms;
+DROP TABLE rooms;
+ALTER TABLE rooms_new RENAME TO rooms;
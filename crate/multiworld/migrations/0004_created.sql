@@ -0,0 +1,2 @@
+ALTER TABLE rooms ADD COLUMN created TEXT NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE rooms SET created = last_activity;
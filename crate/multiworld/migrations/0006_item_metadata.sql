@@ -0,0 +1 @@
+ALTER TABLE rooms ADD COLUMN item_metadata BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+ALTER TABLE rooms ADD COLUMN owner_token BLOB;
+ALTER TABLE rooms ADD COLUMN locked BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE rooms ADD COLUMN queue_corrections BLOB;
@@ -0,0 +1,68 @@
+//! Client settings which are kept across runs, such as which server to connect to.
+
+use {
+    std::{
+        fs as sync_fs,
+        path::PathBuf,
+    },
+    directories::ProjectDirs,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    tokio::{
+        fs,
+        io,
+    },
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)] Io(#[from] io::Error),
+    #[error(transparent)] Json(#[from] serde_json::Error),
+    #[error("failed to find the user's config directory")]
+    MissingHomeDir,
+}
+
+/// Stored as `config.json` in the platform's config directory. Settings which are missing from the file fall back to their defaults.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// The host name or IP address of a server to connect to instead of the public one, e.g. a self-hosted or test server.
+    ///
+    /// Only the public server has a certificate for [`crate::HOSTNAME`], so connections to other servers are unencrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// The port on which `server` accepts connections. Defaults to [`crate::PORT`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl Config {
+    fn path() -> Result<PathBuf, Error> {
+        Ok(ProjectDirs::from("net", "Fenhl", "OoTR Multiworld").ok_or(Error::MissingHomeDir)?.config_dir().join("config.json"))
+    }
+
+    /// Reads the config file, or returns the default config if it doesn't exist.
+    pub fn load_sync() -> Result<Self, Error> {
+        match sync_fs::read(Self::path()?) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), Error> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+
+    /// The host and port of the server to connect to instead of the public one, if any.
+    pub fn custom_server(&self) -> Option<(&str, u16)> {
+        self.server.as_deref().map(|host| (host, self.port.unwrap_or(crate::PORT)))
+    }
+}
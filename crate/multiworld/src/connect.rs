@@ -0,0 +1,127 @@
+//! Opening TCP connections to dual-stack servers.
+//!
+//! Connection attempts are raced as described in [RFC 8305](https://www.rfc-editor.org/rfc/rfc8305) (“Happy Eyeballs”): IPv6 and IPv4 addresses are tried alternately, starting with IPv6, and each attempt gets a head start of [`ATTEMPT_DELAY`] before the next one is started in parallel. This way, a broken IPv6 setup only delays the connection slightly instead of making it fail.
+
+use {
+    std::{
+        collections::VecDeque,
+        net::{
+            SocketAddr,
+            TcpStream as SyncTcpStream,
+            ToSocketAddrs as SyncToSocketAddrs,
+        },
+        sync::mpsc::{
+            self,
+            RecvTimeoutError,
+        },
+        thread,
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+    futures::stream::{
+        FuturesUnordered,
+        StreamExt as _,
+    },
+    itertools::Itertools as _,
+    tokio::{
+        io,
+        net::{
+            TcpStream,
+            ToSocketAddrs,
+            lookup_host,
+        },
+        select,
+        time::{
+            self,
+            sleep,
+            sleep_until,
+        },
+    },
+    crate::{
+        ADDRESS_V4,
+        ADDRESS_V6,
+        PORT,
+    },
+};
+
+/// How long a connection attempt may take before the next address is tried in parallel. This is the value recommended by RFC 8305.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long to wait in total for any of the connection attempts to succeed.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The addresses of the public server.
+pub fn public_server_addrs() -> [SocketAddr; 2] {
+    [(ADDRESS_V6, PORT).into(), (ADDRESS_V4, PORT).into()]
+}
+
+/// Sorts addresses in the order in which they should be tried.
+fn interleave(addrs: impl IntoIterator<Item = SocketAddr>) -> VecDeque<SocketAddr> {
+    let (v6, v4) = addrs.into_iter().partition::<Vec<_>, _>(SocketAddr::is_ipv6);
+    v6.into_iter().interleave(v4).collect()
+}
+
+fn no_addresses() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out connecting to server")
+}
+
+/// Connects to whichever of the given addresses accepts the connection first. Host names are resolved to all of their addresses.
+///
+/// Returns the address which the connection was made to along with the connection, so callers can tell whether IPv6 or IPv4 was used.
+pub async fn tcp(addrs: impl ToSocketAddrs) -> io::Result<(TcpStream, SocketAddr)> {
+    let deadline = time::Instant::now() + TIMEOUT;
+    let mut pending = interleave(lookup_host(addrs).await?);
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.pop_front() {
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        }
+        if attempts.is_empty() { return Err(last_error.unwrap_or_else(no_addresses)) }
+        select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(tcp_stream) => return Ok((tcp_stream, addr)),
+                // start the next attempt right away instead of waiting for the delay
+                Err(e) => last_error = Some(e),
+            },
+            () = sleep(ATTEMPT_DELAY), if !pending.is_empty() => {}
+            () = sleep_until(deadline) => return Err(timed_out()),
+        }
+    }
+}
+
+/// Blocking version of [`tcp`]. Each connection attempt runs on its own thread.
+pub fn tcp_sync(addrs: impl SyncToSocketAddrs) -> io::Result<(SyncTcpStream, SocketAddr)> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut pending = interleave(addrs.to_socket_addrs()?);
+    let (tx, rx) = mpsc::channel();
+    let mut num_attempts = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.pop_front() {
+            let tx = tx.clone();
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            thread::spawn(move || {
+                // the receiver is gone if another attempt already succeeded
+                let _ = tx.send((addr, SyncTcpStream::connect_timeout(&addr, timeout)));
+            });
+            num_attempts += 1;
+        }
+        if num_attempts == 0 { return Err(last_error.unwrap_or_else(no_addresses)) }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(if pending.is_empty() { remaining } else { remaining.min(ATTEMPT_DELAY) }) {
+            Ok((addr, Ok(tcp_stream))) => return Ok((tcp_stream, addr)),
+            Ok((_, Err(e))) => {
+                num_attempts -= 1;
+                last_error = Some(e);
+            }
+            Err(RecvTimeoutError::Timeout) => if Instant::now() >= deadline { return Err(timed_out()) },
+            Err(RecvTimeoutError::Disconnected) => unreachable!("sender is kept alive by this function"),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Finding servers on the local network, so players don't have to type IP addresses.
+//!
+//! A client broadcasts a query over UDP, and each LAN server which speaks the client's protocol version replies with its room list.
+
+use {
+    std::{
+        collections::BTreeMap,
+        io::ErrorKind,
+        net::{
+            IpAddr,
+            Ipv4Addr,
+            UdpSocket as SyncUdpSocket,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+    async_proto::Protocol as _,
+    itertools::Itertools as _,
+    tokio::{
+        io,
+        net::UdpSocket,
+        time::{
+            self,
+            timeout_at,
+        },
+    },
+    crate::{
+        MIN_SERVER_VERSION,
+        RoomInfo,
+        VERSION,
+    },
+};
+
+/// The UDP port on which LAN servers listen for discovery queries.
+pub const PORT: u16 = 24810;
+/// How long clients wait for replies after broadcasting a query.
+pub const TIMEOUT: Duration = Duration::from_millis(500);
+/// Prefixed to all discovery packets so unrelated traffic on the port is ignored.
+const MAGIC: [u8; 4] = *b"OoTM";
+/// Replies are truncated to this many bytes so they fit in a single unfragmented packet and can't be used to amplify traffic.
+pub const MAX_REPLY_LEN: usize = 1200;
+
+/// The servers found by a discovery query, along with their room lists.
+pub type LanServers = BTreeMap<IpAddr, BTreeMap<String, RoomInfo>>;
+
+fn query() -> [u8; 5] {
+    let [m0, m1, m2, m3] = MAGIC;
+    [m0, m1, m2, m3, VERSION]
+}
+
+/// Checks whether a packet received by a server is a discovery query from a client it can talk to.
+pub fn is_query(packet: &[u8]) -> bool {
+    match packet.strip_prefix(&MAGIC) {
+        Some(&[version]) => (MIN_SERVER_VERSION..=VERSION).contains(&version),
+        _ => false,
+    }
+}
+
+/// The packet a server sends in reply to a discovery query. If the room list doesn't fit in [`MAX_REPLY_LEN`], only as many rooms as fit are included.
+pub fn reply(rooms: &BTreeMap<String, RoomInfo>) -> Vec<u8> {
+    // start with the size of an empty room list so the length prefix is accounted for
+    let mut empty = Vec::default();
+    BTreeMap::<String, RoomInfo>::default().write_sync(&mut empty).expect("failed to encode room list");
+    let mut len = MAGIC.len() + empty.len();
+    let mut included = BTreeMap::default();
+    for (name, info) in rooms {
+        let mut entry = Vec::default();
+        name.write_sync(&mut entry).expect("failed to encode room name");
+        info.write_sync(&mut entry).expect("failed to encode room info");
+        len += entry.len();
+        if len > MAX_REPLY_LEN { break }
+        included.insert(name.clone(), *info);
+    }
+    let mut packet = MAGIC.to_vec();
+    included.write_sync(&mut packet).expect("failed to encode room list");
+    packet
+}
+
+fn parse_reply(packet: &[u8]) -> Option<BTreeMap<String, RoomInfo>> {
+    BTreeMap::read_sync(&mut packet.strip_prefix(&MAGIC)?).ok()
+}
+
+/// Broadcasts a discovery query and collects the replies which arrive within [`TIMEOUT`].
+pub async fn discover() -> io::Result<LanServers> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&query(), (Ipv4Addr::BROADCAST, PORT)).await?;
+    let deadline = time::Instant::now() + TIMEOUT;
+    let mut servers = LanServers::default();
+    let mut buf = [0; 65536];
+    while let Ok(res) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, addr) = res?;
+        if let Some(rooms) = parse_reply(&buf[..len]) {
+            servers.insert(addr.ip(), rooms);
+        }
+    }
+    Ok(servers)
+}
+
+/// Like [`discover`], but blocks the current thread.
+pub fn discover_sync() -> io::Result<LanServers> {
+    let socket = SyncUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&query(), (Ipv4Addr::BROADCAST, PORT))?;
+    let deadline = Instant::now() + TIMEOUT;
+    let mut servers = LanServers::default();
+    let mut buf = [0; 65536];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() { break }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => if let Some(rooms) = parse_reply(&buf[..len]) {
+                servers.insert(addr.ip(), rooms);
+            },
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(servers)
+}
+
+pub fn format_lan_server(addr: IpAddr, rooms: &BTreeMap<String, RoomInfo>) -> String {
+    if rooms.is_empty() {
+        format!("{addr} (no rooms)")
+    } else {
+        format!("{addr} ({})", rooms.keys().join(", "))
+    }
+}
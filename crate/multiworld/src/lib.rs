@@ -4,47 +4,216 @@
 use {
     std::{
         collections::{
-            BTreeSet,
+            BTreeMap,
             HashMap,
-            HashSet,
         },
-        mem,
+        convert::TryInto as _,
+        io::prelude::*,
         net::{
             Ipv4Addr,
             Ipv6Addr,
         },
         num::NonZeroU8,
         sync::Arc,
+        time::Duration,
     },
     async_proto::Protocol,
-    async_recursion::async_recursion,
     chrono::prelude::*,
     itertools::Itertools as _,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
     tokio::{
-        net::{
-            TcpStream,
-            tcp::OwnedWriteHalf,
+        io::{
+            self,
+            AsyncRead,
+            AsyncWrite,
+        },
+        net::TcpStream,
+    },
+    tokio_rustls::{
+        TlsConnector,
+        client::TlsStream,
+        rustls::{
+            self,
+            ClientConfig,
+            ClientConnection,
+            OwnedTrustAnchor,
+            RootCertStore,
+            ServerName,
+            StreamOwned,
         },
-        sync::Mutex,
     },
 };
+#[cfg(feature = "server")] use {
+    std::{
+        collections::HashSet,
+        fmt,
+        mem,
+        pin::Pin,
+    },
+    argon2::{
+        Argon2,
+        PasswordHash,
+        PasswordHasher as _,
+        PasswordVerifier as _,
+        password_hash::{
+            SaltString,
+            rand_core::{
+                OsRng,
+                RngCore as _,
+            },
+        },
+    },
+    async_recursion::async_recursion,
+    futures::sink::{
+        Sink,
+        SinkExt as _,
+    },
+    tokio::{
+        io::{
+            AsyncWriteExt as _,
+            WriteHalf,
+        },
+        net::tcp::OwnedWriteHalf,
+        sync::{
+            Mutex,
+            mpsc,
+        },
+    },
+    tokio_tungstenite::tungstenite,
+};
 #[cfg(unix)] use std::os::unix::io::AsRawFd;
 #[cfg(windows)] use std::os::windows::io::AsRawSocket;
 
+pub mod config;
+pub mod connect;
+pub mod discovery;
+#[cfg(feature = "server")] pub mod server;
+#[cfg(feature = "server")] mod v1;
+
 pub const ADDRESS_V4: Ipv4Addr = Ipv4Addr::new(37, 252, 122, 84);
 pub const ADDRESS_V6: Ipv6Addr = Ipv6Addr::new(0x2a02, 0x2770, 0x8, 0, 0x21a, 0x4aff, 0xfee1, 0xf281);
+/// The host name for which the server's TLS certificate is issued.
+pub const HOSTNAME: &str = "midos.house";
 pub const PORT: u16 = 24809;
-pub const VERSION: u8 = 1;
+/// The latest protocol version, which is the one used by this crate's clients.
+pub const VERSION: u8 = 2;
+/// The oldest protocol version the server still supports, so clients which haven't been updated yet keep working during rollouts.
+pub const MIN_SERVER_VERSION: u8 = 1;
+/// Sent by clients instead of a single protocol version to start version negotiation, followed by the oldest and newest versions they support. The server replies with the version it picked.
+///
+/// Version 1 clients send their version instead and expect the server to reply with the same version.
+pub const NEGOTIATE_VERSION: u8 = 0;
+/// How many recent chat messages are kept in [`Room::chat_history`] and sent to clients when they join.
+pub const CHAT_HISTORY_LEN: usize = 32;
+/// The maximum length of a chat message, in characters.
+pub const MAX_CHAT_MESSAGE_LEN: usize = 500;
 
-const TRIFORCE_PIECE: u16 = 0xca;
+#[cfg(feature = "server")] const TRIFORCE_PIECE: u16 = 0xca;
+/// Set in the location keys of items added using [`Room::inject_item`], which weren't found at a location.
+#[cfg(feature = "server")] const INJECTED_ITEM_KEY: u32 = 0x8000_0000;
+/// How long a world stays reserved for a player after they disconnect, so they can reclaim it using their resumption token.
+#[cfg(feature = "server")] const RESERVATION_SECS: i64 = 10 * 60;
 
 #[cfg(unix)] pub type SocketId = std::os::unix::io::RawFd;
 #[cfg(windows)] pub type SocketId = std::os::windows::io::RawSocket;
 
+/// The connection underlying a [`ClientWriter`], which may or may not be encrypted.
+#[cfg(feature = "server")]
+pub enum Transport {
+    Tcp(OwnedWriteHalf),
+    Tls(WriteHalf<tokio_rustls::server::TlsStream<TcpStream>>),
+    /// A WebSocket connection, which may or may not be encrypted. Each message is sent as a separate frame, encoded as JSON text if `json` is `true` and as binary otherwise.
+    WebSocket {
+        sink: Pin<Box<dyn Sink<tungstenite::Message, Error = tungstenite::Error> + Send>>,
+        json: bool,
+    },
+}
+
+#[cfg(feature = "server")]
+impl Transport {
+    async fn write<T: Protocol + Serialize + Sync>(&mut self, msg: &T) -> Result<(), async_proto::WriteError> {
+        match self {
+            Self::Tcp(writer) => msg.write(writer).await,
+            Self::Tls(writer) => msg.write(writer).await,
+            Self::WebSocket { sink, json } => {
+                let frame = if *json {
+                    tungstenite::Message::Text(serde_json::to_string(msg).map_err(|e| async_proto::WriteError::Custom(e.to_string()))?)
+                } else {
+                    let mut buf = Vec::default();
+                    msg.write_sync(&mut buf)?;
+                    tungstenite::Message::Binary(buf)
+                };
+                sink.send(frame).await.map_err(io::Error::other)?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) -> Result<(), async_proto::WriteError> {
+        match self {
+            Self::Tcp(writer) => writer.shutdown().await?,
+            Self::Tls(writer) => writer.shutdown().await?,
+            Self::WebSocket { sink, .. } => sink.close().await.map_err(io::Error::other)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(writer) => f.debug_tuple("Tcp").field(writer).finish(),
+            Self::Tls(writer) => f.debug_tuple("Tls").field(writer).finish(),
+            Self::WebSocket { json, .. } => f.debug_struct("WebSocket").field("json", json).finish_non_exhaustive(),
+        }
+    }
+}
+
+/// The write half of a server's connection to a client.
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub struct ClientWriter {
+    transport: Transport,
+    /// The protocol version agreed on during the handshake, which determines how [`ServerMessage`]s are encoded.
+    pub version: u8,
+}
+
+#[cfg(feature = "server")]
+impl ClientWriter {
+    pub fn new(transport: Transport) -> Self {
+        Self { transport, version: VERSION }
+    }
+
+    /// Sends part of the handshake. The caller is responsible for using the encoding of the client's protocol version.
+    pub async fn write_handshake<T: Protocol + Serialize + Sync>(&mut self, msg: &T) -> Result<(), async_proto::WriteError> {
+        self.transport.write(msg).await
+    }
+
+    /// Sends a message to the client, converting it for clients using an older protocol version. Messages which don't exist in the client's version are skipped.
+    pub async fn write(&mut self, msg: &ServerMessage) -> Result<(), async_proto::WriteError> {
+        if self.version >= 2 {
+            self.transport.write(msg).await
+        } else if let Some(msg) = v1::ServerMessage::from_current(msg) {
+            self.transport.write(&msg).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Closes the connection.
+    pub async fn shutdown(&mut self) -> Result<(), async_proto::WriteError> {
+        self.transport.shutdown().await
+    }
+}
+
 #[cfg(unix)] pub fn socket_id<T: AsRawFd>(socket: &T) -> SocketId { socket.as_raw_fd() }
 #[cfg(windows)] pub fn socket_id<T: AsRawSocket>(socket: &T) -> SocketId { socket.as_raw_socket() }
 
-#[derive(Debug, Clone, Copy, Protocol)]
+#[derive(Debug, Clone, Copy, Protocol, Deserialize, Serialize)]
 pub struct Player {
     pub world: NonZeroU8,
     pub name: [u8; 8],
@@ -61,26 +230,223 @@ impl Player {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Protocol)]
 pub struct Item {
     pub source: NonZeroU8,
+    /// The world the item was sent to. Triforce pieces are added to every world's queue, but this is still the world specified by the sender.
+    pub target: NonZeroU8,
     pub key: u32,
     pub kind: u16,
+    /// When the item was first queued.
+    pub time: DateTime<Utc>,
+}
+
+/// A message sent in a room's text chat.
+#[derive(Debug, Clone, Protocol, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub time: DateTime<Utc>,
+    /// The sender's world and their name at the time they sent the message.
+    pub sender: Player,
+    pub text: String,
 }
 
+/// A manual change to a world's item queue, recorded in [`Room::queue_corrections`].
+#[derive(Debug, Clone, Copy, Protocol)]
+pub struct QueueCorrection {
+    pub time: DateTime<Utc>,
+    pub by: Corrector,
+    pub kind: QueueCorrectionKind,
+    pub world: NonZeroU8,
+    pub item: Item,
+}
+
+#[derive(Debug, Clone, Copy, Protocol)]
+pub enum Corrector {
+    /// A server admin, using `ootrmwd admin`.
+    Admin,
+    /// A room owner, along with the world they had claimed, if any.
+    Owner(Option<NonZeroU8>),
+}
+
+#[derive(Debug, Clone, Copy, Protocol)]
+pub enum QueueCorrectionKind {
+    /// An item already in the queue was added to the end again.
+    Resend,
+    /// An item was removed from the queue.
+    Remove,
+    /// A new item was added to the end of the queue.
+    Inject,
+}
+
+/// Something that happened in a room, which the server reports to its webhooks.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    Created,
+    /// A client has claimed a world, or a disconnected player has resumed their session.
+    WorldClaimed {
+        world: NonZeroU8,
+    },
+    PlayerDisconnected {
+        world: NonZeroU8,
+    },
+    /// An item other than a Triforce piece was sent to a world for the first time.
+    ItemSent {
+        source: NonZeroU8,
+        target: NonZeroU8,
+        key: u32,
+        kind: u16,
+    },
+    /// A Triforce piece was found. These are shared by all worlds, so `total` is the number of pieces found in the room so far.
+    TriforcePiece {
+        source: NonZeroU8,
+        key: u32,
+        total: usize,
+    },
+}
+
+/// A [`RoomEvent`] along with the name of the room it happened in and when.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomEventReport {
+    pub room: String,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: RoomEvent,
+}
+
+/// Reports a room's events to the server. Events are dropped rather than waited on if the server's queue is full, so a slow webhook can't hold up the room.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    room: String,
+    tx: mpsc::Sender<RoomEventReport>,
+}
+
+#[cfg(feature = "server")]
+impl EventSender {
+    pub fn new(room: String, tx: mpsc::Sender<RoomEventReport>) -> Self {
+        Self { room, tx }
+    }
+
+    fn send(&self, event: RoomEvent) {
+        match self.tx.try_send(RoomEventReport { room: self.room.clone(), time: Utc::now(), event }) {
+            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => eprintln!("{} event queue is full, dropping {event:?} in room {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), self.room),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
 #[derive(Debug)]
 pub struct Room {
-    pub password: String,
-    pub clients: HashMap<SocketId, (Option<Player>, Arc<Mutex<OwnedWriteHalf>>)>,
+    /// A salted hash of the room password in PHC string format, as produced by [`hash_password`]. `None` if the room has no password.
+    pub password_hash: Option<String>,
+    pub clients: HashMap<SocketId, (Option<Player>, Arc<Mutex<ClientWriter>>)>,
+    /// The number of worlds in the seed, if known from a spoiler log attached when the room was created.
+    pub world_count: Option<NonZeroU8>,
+    /// Identifies the seed, if known from a spoiler log attached when the room was created. Spoiler logs attached when joining the room must match this.
+    pub seed: Option<SeedInfo>,
+    /// Connections which receive room state and [`ServerMessage::ItemSent`] events but can't claim worlds or send items.
+    pub spectators: HashMap<SocketId, Arc<Mutex<ClientWriter>>>,
     pub base_queue: Vec<Item>,
     pub player_queues: HashMap<NonZeroU8, Vec<Item>>,
+    pub last_activity: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    /// Claimed worlds along with the resumption tokens of the players who claimed them.
+    pub reservations: HashMap<NonZeroU8, Reservation>,
+    /// A secret which grants owner rights when sent with [`RoomClientMessage::ClaimOwnership`]. `None` for rooms created before room ownership was introduced.
+    pub owner_token: Option<[u8; 16]>,
+    /// Connections (clients or spectators) which may use the room's moderation commands.
+    pub owners: HashSet<SocketId>,
+    /// Whether new clients are prevented from joining. Disconnected players can still resume their sessions, and spectators can still join.
+    pub locked: bool,
+    /// Manual changes to the item queues by owners or admins, in the order they were made.
+    pub queue_corrections: Vec<QueueCorrection>,
+    /// Where to report this room's events. `None` if the server has no webhooks configured.
+    pub events: Option<EventSender>,
+    /// The most recent chat messages, oldest first. Not persisted across server restarts.
+    pub chat_history: Vec<ChatMessage>,
+}
+
+/// The parts of a spoiler log which identify a seed.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, PartialEq, Eq, Protocol)]
+pub struct SeedInfo {
+    /// The names of the icons shown on the file select screen.
+    pub file_hash: Vec<String>,
+    /// Empty if the seed was generated without player names.
+    pub player_names: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, Protocol)]
+pub struct Reservation {
+    pub token: [u8; 16],
+    /// `None` while the player is connected. Otherwise, the world can be claimed by other players after this time.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "server")]
+impl Reservation {
+    /// Starts the grace period during which a disconnected player can reclaim their world.
+    pub fn start_expiry(&mut self) {
+        self.expires.get_or_insert_with(|| Utc::now() + chrono::Duration::seconds(RESERVATION_SECS));
+    }
+
+    fn is_active(&self) -> bool {
+        self.expires.map_or(true, |expires| expires > Utc::now())
+    }
+}
+
+/// Information about a room that's shown to clients in the lobby.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Protocol, Deserialize, Serialize)]
+pub struct RoomInfo {
+    pub created: DateTime<Utc>,
+    /// The number of connected clients which have claimed a world.
+    pub num_players: u8,
+    /// The number of connected clients, including those without a world.
+    pub num_clients: u8,
+    pub password_protected: bool,
+    pub locked: bool,
+}
+
+/// Hashes a room password for storage in [`Room::password_hash`].
+#[cfg(feature = "server")]
+pub fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+    Ok(Argon2::default().hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))?.to_string())
 }
 
+/// Checks a password against a hash produced by [`hash_password`]. The comparison is done in constant time.
+///
+/// This takes a while by design, so async code should call it on a blocking thread.
+#[cfg(feature = "server")]
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash).and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash)).is_ok()
+}
+
+#[cfg(feature = "server")]
 impl Room {
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            created: self.created,
+            num_players: self.clients.values().filter(|(player, _)| player.is_some()).count().try_into().expect("too many players"),
+            num_clients: self.clients.len().try_into().expect("too many clients"),
+            password_protected: self.password_hash.is_some(),
+            locked: self.locked,
+        }
+    }
+
+    /// Reports an event to the server's webhooks, if any.
+    pub fn report_event(&self, event: RoomEvent) {
+        if let Some(ref events) = self.events {
+            events.send(event);
+        }
+    }
+
     async fn write(&mut self, client_id: SocketId, msg: &ServerMessage) {
         if let Some((_, writer)) = self.clients.get(&client_id) {
             let mut writer = writer.lock().await;
-            if let Err(e) = msg.write(&mut *writer).await {
+            if let Err(e) = writer.write(msg).await {
                 eprintln!("{} error sending message: {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), e);
                 drop(writer);
                 self.remove_client(client_id).await;
@@ -88,33 +454,65 @@ impl Room {
         }
     }
 
-    async fn write_all(&mut self, msg: &ServerMessage) {
+    /// Sends a message to all clients and spectators.
+    pub async fn write_all(&mut self, msg: &ServerMessage) {
         let mut notified = HashSet::new();
         while let Some((&client_id, (_, writer))) = self.clients.iter().find(|&(client_id, _)| !notified.contains(client_id)) {
             let mut writer = writer.lock().await;
-            if let Err(e) = msg.write(&mut *writer).await {
+            if let Err(e) = writer.write(msg).await {
                 eprintln!("{} error sending message: {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), e);
                 drop(writer);
                 self.remove_client(client_id).await;
             }
             notified.insert(client_id);
         }
+        self.write_spectators(msg).await;
+    }
+
+    async fn write_spectators(&mut self, msg: &ServerMessage) {
+        let mut failed = Vec::default();
+        for (&spectator_id, writer) in &self.spectators {
+            if let Err(e) = writer.lock().await.write(msg).await {
+                eprintln!("{} error sending message: {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), e);
+                failed.push(spectator_id);
+            }
+        }
+        for spectator_id in failed {
+            self.spectators.remove(&spectator_id);
+        }
     }
 
-    pub async fn add_client(&mut self, client_id: SocketId, writer: Arc<Mutex<OwnedWriteHalf>>) {
+    pub async fn add_client(&mut self, client_id: SocketId, writer: Arc<Mutex<ClientWriter>>) {
         // the client doesn't need to be told that it has connected, so notify everyone *before* adding it
         self.write_all(&ServerMessage::ClientConnected).await;
         self.clients.insert(client_id, (None, writer));
     }
 
+    /// Adds a spectator to the room. Unlike clients, spectators aren't announced to the other clients.
+    pub fn add_spectator(&mut self, client_id: SocketId, writer: Arc<Mutex<ClientWriter>>) {
+        self.spectators.insert(client_id, writer);
+    }
+
+    /// Returns whether the given connection is in the room, either as a client or as a spectator.
     pub fn has_client(&self, client_id: SocketId) -> bool {
-        self.clients.contains_key(&client_id)
+        self.clients.contains_key(&client_id) || self.spectators.contains_key(&client_id)
+    }
+
+    /// Returns the connected client which has claimed the given world, if any.
+    pub fn client_for_world(&self, world: NonZeroU8) -> Option<SocketId> {
+        self.clients.iter().find(|(_, (player, _))| player.map_or(false, |p| p.world == world)).map(|(&client_id, _)| client_id)
     }
 
     #[async_recursion]
     pub async fn remove_client(&mut self, client_id: SocketId) {
+        self.owners.remove(&client_id);
+        if self.spectators.remove(&client_id).is_some() { return }
         if let Some((player, _)) = self.clients.remove(&client_id) {
             let msg = if let Some(Player { world, .. }) = player {
+                if let Some(reservation) = self.reservations.get_mut(&world) {
+                    reservation.start_expiry();
+                }
+                self.report_event(RoomEvent::PlayerDisconnected { world });
                 ServerMessage::PlayerDisconnected(world)
             } else {
                 ServerMessage::UnregisteredClientDisconnected
@@ -123,20 +521,94 @@ impl Room {
         }
     }
 
+    /// Removes a client which has asked to leave the room. Unlike when a connection is lost, the client's world is freed up immediately rather than being reserved.
+    pub async fn leave(&mut self, client_id: SocketId) {
+        let player = self.clients.get(&client_id).and_then(|&(player, _)| player);
+        self.remove_client(client_id).await;
+        if let Some(player) = player {
+            self.reservations.remove(&player.world);
+        }
+    }
+
+    /// Adds a client to the room in place of the player who was issued the given resumption token, disconnecting that player's previous connection if it's still open.
+    ///
+    /// Returns the world reserved for the token, which should be claimed using [`Room::load_player`] after telling the client that it has entered the room. Returns `None` if the token is invalid or has expired, in which case the client is not added.
+    pub async fn resume_client(&mut self, client_id: SocketId, writer: Arc<Mutex<ClientWriter>>, token: [u8; 16]) -> Option<NonZeroU8> {
+        let world = *self.reservations.iter().find(|(_, reservation)| reservation.token == token && reservation.is_active())?.0;
+        // the server may not have noticed yet that the previous connection is dead
+        if let Some(prev_client) = self.client_for_world(world) {
+            self.remove_client(prev_client).await;
+        }
+        if let Some(reservation) = self.reservations.get_mut(&world) {
+            reservation.expires = None;
+        }
+        self.add_client(client_id, writer).await;
+        Some(world)
+    }
+
+    /// Removes a client or spectator from the room and closes its connection after sending it the given reason as an error message. If it had claimed a world, it can't reclaim it using its resumption token.
+    ///
+    /// The client's session notices that it's no longer in the room the next time it sends a message and ends.
+    pub async fn disconnect(&mut self, client_id: SocketId, reason: &str) {
+        let client = if let Some((player, writer)) = self.clients.get(&client_id) {
+            Some((*player, Arc::clone(writer)))
+        } else {
+            self.spectators.get(&client_id).map(|writer| (None, Arc::clone(writer)))
+        };
+        if let Some((player, writer)) = client {
+            self.remove_client(client_id).await;
+            if let Some(player) = player {
+                self.reservations.remove(&player.world);
+            }
+            let mut writer = writer.lock().await;
+            let _ = writer.write(&ServerMessage::Error(reason.to_owned())).await;
+            let _ = writer.shutdown().await;
+        }
+    }
+
+    /// Grants owner rights to the given client or spectator and sends it the room's owner token.
+    pub async fn add_owner(&mut self, client_id: SocketId) {
+        self.owners.insert(client_id);
+        if let Some(token) = self.owner_token {
+            let writer = if let Some((_, writer)) = self.clients.get(&client_id) {
+                Arc::clone(writer)
+            } else if let Some(writer) = self.spectators.get(&client_id) {
+                Arc::clone(writer)
+            } else {
+                return
+            };
+            let res = writer.lock().await.write(&ServerMessage::OwnerToken(token)).await;
+            if let Err(e) = res {
+                eprintln!("{} error sending message: {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), e);
+                self.remove_client(client_id).await;
+            }
+        }
+    }
+
     /// Moves a player from unloaded (no world assigned) to the given `world`.
     pub async fn load_player(&mut self, client_id: SocketId, world: NonZeroU8) -> bool {
         if self.clients.iter().any(|(&iter_client_id, (iter_player, _))| iter_player.as_ref().map_or(false, |p| p.world == world) && iter_client_id != client_id) {
             return false
         }
+        if self.reservations.get(&world).map_or(false, |reservation| reservation.expires.is_some() && reservation.is_active()) {
+            // reserved for a disconnected player
+            return false
+        }
         let prev_player = &mut self.clients.get_mut(&client_id).expect("no such client").0;
         if let Some(player) = prev_player {
             let prev_world = mem::replace(&mut player.world, world);
             if prev_world == world { return true }
+            self.reservations.remove(&prev_world);
             self.write_all(&ServerMessage::ResetPlayerId(prev_world)).await;
         } else {
             *prev_player = Some(Player::new(world));
         }
+        let mut token = [0; 16];
+        OsRng.fill_bytes(&mut token);
+        self.reservations.insert(world, Reservation { token, expires: None });
+        self.report_event(RoomEvent::WorldClaimed { world });
         self.write_all(&ServerMessage::PlayerId(world)).await;
+        self.write(client_id, &ServerMessage::ResumptionToken(token)).await;
         let queue = self.player_queues.get(&world).unwrap_or(&self.base_queue).iter().map(|item| item.kind).collect::<Vec<_>>();
         if !queue.is_empty() {
             self.write(client_id, &ServerMessage::ItemQueue(queue)).await;
@@ -144,12 +616,88 @@ impl Room {
         true
     }
 
+    /// Adds another copy of the item with the given source world and location key to the end of the given world's queue. Returns `false` if the world hasn't received such an item.
+    pub async fn resend_item(&mut self, by: Corrector, world: NonZeroU8, source: NonZeroU8, key: u32) -> bool {
+        if let Some(&item) = self.player_queues.get(&world).unwrap_or(&self.base_queue).iter().find(|item| item.source == source && item.key == key) {
+            self.player_queues.entry(world).or_insert_with(|| self.base_queue.clone()).push(item);
+            self.record_correction(by, QueueCorrectionKind::Resend, world, item).await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes the item with the given source world and location key from the given world's queue. Returns `false` if there is no such item.
+    ///
+    /// This can't take back an item the player has already received, so it should only be used for items which haven't been sent to the game yet.
+    pub async fn remove_item(&mut self, by: Corrector, world: NonZeroU8, source: NonZeroU8, key: u32) -> bool {
+        if let Some(idx) = self.player_queues.get(&world).unwrap_or(&self.base_queue).iter().position(|item| item.source == source && item.key == key) {
+            let item = self.player_queues.entry(world).or_insert_with(|| self.base_queue.clone()).remove(idx);
+            self.record_correction(by, QueueCorrectionKind::Remove, world, item).await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds a new item to the end of the given world's queue. Since it wasn't found at a location, it's recorded with a location key that has the highest bit set and counts the items injected in this room, so it can be told apart from other items.
+    pub async fn inject_item(&mut self, by: Corrector, world: NonZeroU8, source: NonZeroU8, kind: u16) {
+        let num_injected = self.queue_corrections.iter().filter(|correction| matches!(correction.kind, QueueCorrectionKind::Inject)).count();
+        let key = INJECTED_ITEM_KEY | u32::try_from(num_injected).expect("too many injected items");
+        let item = Item { source, target: world, key, kind, time: Utc::now() };
+        self.player_queues.entry(world).or_insert_with(|| self.base_queue.clone()).push(item);
+        self.record_correction(by, QueueCorrectionKind::Inject, world, item).await;
+    }
+
+    /// Adds a correction to the audit trail and sends the corrected queue to the affected client.
+    async fn record_correction(&mut self, by: Corrector, kind: QueueCorrectionKind, world: NonZeroU8, item: Item) {
+        self.queue_corrections.push(QueueCorrection { time: Utc::now(), by, kind, world, item });
+        if let Some(client_id) = self.client_for_world(world) {
+            let queue = self.player_queues.get(&world).unwrap_or(&self.base_queue).iter().map(|item| item.kind).collect();
+            self.write(client_id, &ServerMessage::ItemQueue(queue)).await;
+        }
+    }
+
+    /// Returns every item that has been sent by a player in this room, in the order they were sent.
+    ///
+    /// The log isn't affected by [`Room::queue_corrections`]: resent items are listed once, removed items are still listed, and injected items aren't listed since no player sent them.
+    pub fn item_log(&self) -> Vec<Item> {
+        let removed = self.queue_corrections.iter().filter(|correction| matches!(correction.kind, QueueCorrectionKind::Remove)).map(|correction| &correction.item);
+        let mut log = Vec::<Item>::default();
+        // player queues start out as copies of the base queue and resent items appear twice, so skip items that are already logged to avoid duplicates
+        for item in self.base_queue.iter().chain(self.player_queues.values().flatten()).chain(removed) {
+            if item.key & INJECTED_ITEM_KEY == 0 && !log.iter().any(|logged| logged.source == item.source && logged.key == item.key) {
+                log.push(*item);
+            }
+        }
+        log.sort_by_key(|item| item.time);
+        log
+    }
+
+    /// Notifies all clients of the measured round-trip time to the given client, if it has claimed a world. Spectators' latencies aren't reported.
+    pub async fn set_latency(&mut self, client_id: SocketId, rtt: Duration) {
+        if let Some(&(Some(player), _)) = self.clients.get(&client_id) {
+            self.write_all(&ServerMessage::PlayerLatency(player.world, rtt)).await;
+        }
+    }
+
     pub async fn unload_player(&mut self, client_id: SocketId) {
         if let Some(prev_player) = self.clients.get_mut(&client_id).expect("no such client").0.take() {
+            self.reservations.remove(&prev_player.world);
             self.write_all(&ServerMessage::ResetPlayerId(prev_player.world)).await;
         }
     }
 
+    /// Unassigns the given world from whoever has claimed it, including a disconnected player's reservation. Returns `false` if the world wasn't assigned.
+    pub async fn unassign_world(&mut self, world: NonZeroU8) -> bool {
+        if let Some(client_id) = self.client_for_world(world) {
+            self.unload_player(client_id).await;
+            true
+        } else {
+            self.reservations.remove(&world).is_some()
+        }
+    }
+
     pub async fn set_player_name(&mut self, client_id: SocketId, name: [u8; 8]) -> bool {
         if let Some(ref mut player) = self.clients.get_mut(&client_id).expect("no such client").0 {
             let world = player.world;
@@ -162,15 +710,32 @@ impl Room {
         }
     }
 
+    /// Sends a chat message from the given client to everyone in the room and adds it to the history. Returns `false` if the client hasn't claimed a world.
+    pub async fn send_chat_message(&mut self, client_id: SocketId, text: String) -> bool {
+        if let Some(sender) = self.clients.get(&client_id).expect("no such client").0 {
+            let msg = ChatMessage { time: Utc::now(), sender, text };
+            if self.chat_history.len() >= CHAT_HISTORY_LEN {
+                self.chat_history.remove(0);
+            }
+            self.chat_history.push(msg.clone());
+            self.write_all(&ServerMessage::ChatMessage(msg)).await;
+            true
+        } else {
+            false
+        }
+    }
+
     pub async fn queue_item(&mut self, source_client: SocketId, key: u32, kind: u16, target_world: NonZeroU8) -> bool {
         if let Some(source) = self.clients.get(&source_client).expect("no such client").0.map(|source_player| source_player.world) {
             if kind == TRIFORCE_PIECE {
                 if !self.base_queue.iter().any(|item| item.source == source && item.key == key) {
-                    let item = Item { source, key, kind };
+                    self.write_spectators(&ServerMessage::ItemSent { source, target: target_world, key, kind }).await;
+                    let item = Item { source, target: target_world, key, kind, time: Utc::now() };
                     self.base_queue.push(item);
                     for queue in self.player_queues.values_mut() {
                         queue.push(item);
                     }
+                    self.report_event(RoomEvent::TriforcePiece { source, key, total: self.base_queue.iter().filter(|item| item.kind == TRIFORCE_PIECE).count() });
                     let msg = ServerMessage::GetItem(kind);
                     let player_clients = self.clients.iter()
                         .filter_map(|(&target_client, (p, _))| if p.map_or(false, |p| p.world != source) { Some(target_client) } else { None })
@@ -181,7 +746,9 @@ impl Room {
                 }
             } else {
                 if !self.player_queues.get(&target_world).map_or(false, |queue| queue.iter().any(|item| item.source == source && item.key == key)) {
-                    self.player_queues.entry(target_world).or_insert_with(|| self.base_queue.clone()).push(Item { source, key, kind });
+                    self.player_queues.entry(target_world).or_insert_with(|| self.base_queue.clone()).push(Item { source, target: target_world, key, kind, time: Utc::now() });
+                    self.write_spectators(&ServerMessage::ItemSent { source, target: target_world, key, kind }).await;
+                    self.report_event(RoomEvent::ItemSent { source, target: target_world, key, kind });
                     if let Some((&target_client, _)) = self.clients.iter().find(|(_, (p, _))| p.map_or(false, |p| p.world == target_world)) {
                         self.write(target_client, &ServerMessage::GetItem(kind)).await;
                     }
@@ -194,19 +761,35 @@ impl Room {
     }
 }
 
-#[derive(Protocol)]
+#[derive(Protocol, Deserialize, Serialize)]
 pub enum LobbyClientMessage {
     JoinRoom {
         name: String,
         password: String,
+        /// The contents of the seed's spoiler log (in JSON format), if any. If the room was created with a spoiler log, this is rejected if it's for a different seed.
+        spoiler_log: Option<String>,
     },
     CreateRoom {
         name: String,
         password: String,
+        /// The contents of the seed's spoiler log (in JSON format), if any. This lets the server reject invalid world numbers.
+        spoiler_log: Option<String>,
+    },
+    /// Rejoins a room after a disconnect, reclaiming the world for which the token was issued.
+    Resume {
+        room: String,
+        token: [u8; 16],
     },
+    /// Joins a room as a spectator.
+    Spectate {
+        name: String,
+        password: String,
+    },
+    /// Response to [`ServerMessage::Ping`]. Encoded the same way as [`RoomClientMessage::Pong`], so a reply to a ping sent before joining or leaving a room is still understood.
+    Pong,
 }
 
-#[derive(Protocol)]
+#[derive(Protocol, Deserialize, Serialize)]
 pub enum RoomClientMessage {
     /// Claims a world.
     PlayerId(NonZeroU8),
@@ -219,18 +802,56 @@ pub enum RoomClientMessage {
         kind: u16,
         target_world: NonZeroU8,
     },
+    /// Response to [`ServerMessage::Ping`].
+    Pong,
+    /// Regains owner rights after reconnecting, using the token from [`ServerMessage::OwnerToken`].
+    ClaimOwnership([u8; 16]),
+    /// Grants owner rights to the player with the given world. Owners only.
+    AddOwner(NonZeroU8),
+    /// Disconnects the player with the given world from the room. Owners only.
+    KickPlayer(NonZeroU8),
+    /// Unassigns the given world, so it can be claimed by someone else. Owners only.
+    UnassignWorld(NonZeroU8),
+    /// Changes the room password. An empty string removes the password. Owners only.
+    SetPassword(String),
+    /// Prevents or allows new clients joining the room. Owners only.
+    SetLocked(bool),
+    /// Adds another copy of an item to the end of a world's queue, e.g. if it was lost in a game crash. Owners only.
+    ResendItem {
+        world: NonZeroU8,
+        source: NonZeroU8,
+        key: u32,
+    },
+    /// Removes an item which hasn't been received yet from a world's queue. Owners only.
+    RemoveItem {
+        world: NonZeroU8,
+        source: NonZeroU8,
+        key: u32,
+    },
+    /// Adds a new item to the end of a world's queue. Owners only.
+    InjectItem {
+        world: NonZeroU8,
+        source: NonZeroU8,
+        kind: u16,
+    },
+    /// Sends a message to the room's text chat. Requires a claimed world.
+    ChatMessage(String),
+    /// Leaves the room and returns to the lobby. The server replies with [`ServerMessage::LeftRoom`], after which [`LobbyClientMessage`]s can be sent again.
+    LeaveRoom,
 }
 
-#[derive(Debug, Clone, Protocol)]
+#[derive(Debug, Clone, Protocol, Deserialize, Serialize)]
 pub enum ServerMessage {
     /// An error has occurred. Contains a human-readable error message.
     Error(String),
     /// A new room has been created.
-    NewRoom(String),
+    NewRoom(String, RoomInfo),
     /// You have created or joined a room.
     EnterRoom {
         players: Vec<Player>,
         num_unassigned_clients: u8,
+        /// The most recent messages from the room's text chat, oldest first.
+        chat_history: Vec<ChatMessage>,
     },
     /// A previously unassigned world has been taken by a client.
     PlayerId(NonZeroU8),
@@ -250,6 +871,35 @@ pub enum ServerMessage {
     ItemQueue(Vec<u16>),
     /// You have received a new item, add it to the end of your item queue.
     GetItem(u16),
+    /// A room has been deleted.
+    DeleteRoom(String),
+    /// The server is about to restart at the given time. Clients will be disconnected and have to reconnect afterwards.
+    PrepareRestart(DateTime<Utc>),
+    /// The number of clients or players in a room has changed.
+    UpdateRoom(String, RoomInfo),
+    /// You have claimed a world. If you get disconnected, you can use this token with [`LobbyClientMessage::Resume`] to reclaim it.
+    ///
+    /// Replaces any previously received token.
+    ResumptionToken([u8; 16]),
+    /// Sent periodically to check whether you're still connected. Reply with [`LobbyClientMessage::Pong`] or [`RoomClientMessage::Pong`] or you will be disconnected.
+    Ping,
+    /// The round-trip time between the server and a player's client has been measured.
+    PlayerLatency(NonZeroU8, Duration),
+    /// A player has sent an item. Only sent to spectators.
+    ItemSent {
+        source: NonZeroU8,
+        target: NonZeroU8,
+        key: u32,
+        kind: u16,
+    },
+    /// You are an owner of this room. If you get disconnected, you can use this token with [`RoomClientMessage::ClaimOwnership`] to regain owner rights.
+    OwnerToken([u8; 16]),
+    /// A player has sent a message to the room's text chat.
+    ChatMessage(ChatMessage),
+    /// You have left the room as requested using [`RoomClientMessage::LeaveRoom`] and are back in the lobby. Contains the current list of rooms.
+    LeftRoom(BTreeMap<String, RoomInfo>),
+    /// Something you tried to do has been rejected. Contains a human-readable message. Unlike [`ServerMessage::Error`], this doesn't end the session.
+    Warning(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -260,21 +910,62 @@ pub enum ClientError {
     VersionMismatch(u8),
 }
 
-pub async fn handshake(tcp_stream: &mut TcpStream) -> Result<BTreeSet<String>, ClientError> {
-    VERSION.write(tcp_stream).await?;
-    let server_version = u8::read(tcp_stream).await?;
+fn tls_client_config() -> Arc<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)));
+    Arc::new(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth())
+}
+
+fn server_name() -> ServerName {
+    HOSTNAME.try_into().expect("HOSTNAME is not a valid DNS name")
+}
+
+/// Starts a TLS session on a TCP connection to the server. This should be called before [`handshake`].
+///
+/// The server also accepts unencrypted connections, but those should only be used on local networks. If this fails with an error for which [`tls_unsupported`] returns `true`, the server doesn't have TLS set up, in which case clients fall back to an unencrypted connection.
+pub async fn connect_tls(tcp_stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+    TlsConnector::from(tls_client_config()).connect(server_name(), tcp_stream).await
+}
+
+/// Blocking version of [`connect_tls`]. The TLS handshake itself happens lazily when the returned stream is first used, or when it's completed explicitly using `complete_io`.
+pub fn connect_tls_sync(tcp_stream: std::net::TcpStream) -> Result<StreamOwned<ClientConnection, std::net::TcpStream>, rustls::Error> {
+    Ok(StreamOwned::new(ClientConnection::new(tls_client_config(), server_name())?, tcp_stream))
+}
+
+/// Checks whether an error from the TLS handshake means that the server doesn't speak TLS at all, i.e. it closed the connection or replied with something other than TLS records.
+///
+/// This is the only case in which clients fall back to an unencrypted connection. Other errors, such as an invalid certificate, may mean that someone is tampering with the connection, so they're reported instead.
+pub fn tls_unsupported(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => true,
+        io::ErrorKind::InvalidData => matches!(e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()), Some(rustls::Error::CorruptMessage)),
+        _ => false,
+    }
+}
+
+pub async fn handshake(stream: &mut (impl AsyncRead + AsyncWrite + Unpin + Send)) -> Result<BTreeMap<String, RoomInfo>, ClientError> {
+    NEGOTIATE_VERSION.write(stream).await?;
+    // this crate's clients only speak the latest version, older versions are only supported by the server
+    VERSION.write(stream).await?;
+    VERSION.write(stream).await?;
+    let server_version = u8::read(stream).await?;
     if server_version != VERSION { return Err(ClientError::VersionMismatch(server_version)) }
-    Ok(BTreeSet::read(tcp_stream).await?)
+    Ok(BTreeMap::read(stream).await?)
 }
 
-pub fn handshake_sync(tcp_stream: &mut std::net::TcpStream) -> Result<BTreeSet<String>, ClientError> {
-    VERSION.write_sync(tcp_stream)?;
-    let server_version = u8::read_sync(tcp_stream)?;
+pub fn handshake_sync(stream: &mut (impl Read + Write)) -> Result<BTreeMap<String, RoomInfo>, ClientError> {
+    NEGOTIATE_VERSION.write_sync(stream)?;
+    VERSION.write_sync(stream)?;
+    VERSION.write_sync(stream)?;
+    let server_version = u8::read_sync(stream)?;
     if server_version != VERSION { return Err(ClientError::VersionMismatch(server_version)) }
-    Ok(BTreeSet::read_sync(tcp_stream)?)
+    Ok(BTreeMap::read_sync(stream)?)
 }
 
-fn render_filename(name: [u8; 8]) -> String {
+pub fn render_filename(name: [u8; 8]) -> String {
     let filename_encoding = [
         '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'あ', 'い', 'う', 'え', 'お', 'か',
         'き', 'く', 'け', 'こ', 'さ', 'し', 'す', 'せ', 'そ', 'た', 'ち', 'つ', 'て', 'と', 'な', 'に',
@@ -296,20 +987,60 @@ fn render_filename(name: [u8; 8]) -> String {
     name.into_iter().map(|c| filename_encoding[usize::from(c)]).collect()
 }
 
-pub fn format_room_state(players: &[Player], num_unassigned_clients: u8, my_world: Option<NonZeroU8>) -> String {
+pub fn format_room_info(info: &RoomInfo) -> String {
+    format!(
+        "{} world{} claimed, {} client{} connected, created {}{}{}",
+        info.num_players, if info.num_players == 1 { "" } else { "s" },
+        info.num_clients, if info.num_clients == 1 { "" } else { "s" },
+        info.created.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+        if info.password_protected { "" } else { ", no password" },
+        if info.locked { ", locked" } else { "" },
+    )
+}
+
+/// Returns the reason the server would reject the given chat message, if any. Leading and trailing whitespace is trimmed by the server, so it's ignored here.
+pub fn chat_message_error(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        Some("chat message must not be empty".to_owned())
+    } else if text.contains('\0') {
+        Some("chat message must not contain null characters".to_owned())
+    } else if text.chars().count() > MAX_CHAT_MESSAGE_LEN {
+        Some(format!("chat message too long (maximum {MAX_CHAT_MESSAGE_LEN} characters)"))
+    } else {
+        None
+    }
+}
+
+pub fn format_chat_message(msg: &ChatMessage) -> String {
+    let sender = if msg.sender.name == Player::DEFAULT_NAME {
+        format!("World {}", msg.sender.world)
+    } else {
+        format!("{} (world {})", render_filename(msg.sender.name), msg.sender.world)
+    };
+    format!("[{}] {sender}: {}", msg.time.with_timezone(&Local).format("%H:%M"), msg.text)
+}
+
+pub fn format_room_state(players: &[Player], num_unassigned_clients: u8, my_world: Option<NonZeroU8>, latencies: &HashMap<NonZeroU8, Duration>) -> String {
     match (players.len(), num_unassigned_clients) {
-        (0, 0) => unreachable!(), // the current client should always be in the room
+        (0, 0) => "empty room".to_owned(), // only possible for spectators
         (0, unassigned) => format!("{unassigned} client{} with no world", if unassigned == 1 { "" } else { "s" }),
         (_, unassigned) => {
             let mut buf = players.iter()
-                .map(|player| if player.name == Player::DEFAULT_NAME {
-                    if my_world == Some(player.world) {
-                        format!("{}. [create save file 1 to set name]", player.world)
+                .map(|player| {
+                    let mut line = if player.name == Player::DEFAULT_NAME {
+                        if my_world == Some(player.world) {
+                            format!("{}. [create save file 1 to set name]", player.world)
+                        } else {
+                            format!("{}. [unnamed]", player.world)
+                        }
                     } else {
-                        format!("{}. [unnamed]", player.world)
+                        format!("{}. {}", player.world, render_filename(player.name))
+                    };
+                    if let Some(rtt) = latencies.get(&player.world) {
+                        line.push_str(&format!(" ({}ms)", rtt.as_millis()));
                     }
-                } else {
-                    format!("{}. {}", player.world, render_filename(player.name))
+                    line
                 })
                 .join("\r\n");
             if unassigned > 0 {
@@ -0,0 +1,907 @@
+//! The server side of the protocol, shared by `ootrmwd` and the LAN server embedded in the Project64 app.
+
+use {
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+            HashMap,
+            HashSet,
+        },
+        convert::Infallible as Never,
+        net::{
+            IpAddr,
+            Ipv4Addr,
+        },
+        num::NonZeroU8,
+        pin::Pin,
+        sync::Arc,
+        time::Duration,
+    },
+    argon2::password_hash::rand_core::{
+        OsRng,
+        RngCore as _,
+    },
+    async_proto::Protocol,
+    chrono::prelude::*,
+    futures::{
+        future::{
+            BoxFuture,
+            Future,
+        },
+        sink::Sink,
+        stream::{
+            Stream,
+            StreamExt as _,
+            TryStreamExt as _,
+        },
+    },
+    serde::{
+        Deserialize,
+        de::DeserializeOwned,
+    },
+    sqlx::{
+        SqlitePool,
+        migrate::{
+            MigrateError,
+            Migrator,
+        },
+        sqlite::SqlitePoolOptions,
+    },
+    tokio::{
+        io::{
+            self,
+            AsyncRead,
+        },
+        net::{
+            TcpListener,
+            TcpStream,
+            UdpSocket,
+        },
+        select,
+        sync::{
+            Mutex,
+            RwLock,
+            broadcast,
+            mpsc,
+            watch,
+        },
+        time::{
+            Instant,
+            interval,
+        },
+    },
+    tokio_rustls::TlsAcceptor,
+    tokio_stream::wrappers::ReceiverStream,
+    tokio_tungstenite::tungstenite::{
+        self,
+        handshake::server::{
+            Callback,
+            ErrorResponse,
+            Request,
+            Response,
+        },
+    },
+    crate::{
+        ClientWriter,
+        Corrector,
+        EventSender,
+        LobbyClientMessage,
+        Player,
+        Room,
+        RoomClientMessage,
+        RoomEvent,
+        RoomEventReport,
+        RoomInfo,
+        SeedInfo,
+        ServerMessage,
+        Transport,
+        discovery,
+        server::rate_limit::JoinLimiter,
+    },
+};
+
+pub mod rate_limit;
+
+/// The database schema in which rooms are persisted.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error(transparent)] Io(#[from] io::Error),
+    #[error(transparent)] PasswordHash(#[from] argon2::password_hash::Error),
+    #[error(transparent)] Read(#[from] async_proto::ReadError),
+    #[error(transparent)] Sql(#[from] sqlx::Error),
+    #[error(transparent)] Task(#[from] tokio::task::JoinError),
+    #[error(transparent)] Write(#[from] async_proto::WriteError),
+    #[error("client did not respond to {} heartbeats", MAX_MISSED_HEARTBEATS)]
+    MissedHeartbeats,
+    #[error("protocol version mismatch: client doesn't support any version from {} to {}", crate::MIN_SERVER_VERSION, crate::VERSION)]
+    VersionMismatch,
+}
+
+/// The parts of an OoTR spoiler log that are used to validate a room.
+#[derive(Deserialize)]
+struct SpoilerLog {
+    file_hash: [String; 5],
+    settings: SpoilerLogSettings,
+}
+
+#[derive(Deserialize)]
+struct SpoilerLogSettings {
+    world_count: NonZeroU8,
+    /// Only present if the seed was generated with player names.
+    #[serde(default)]
+    player_names: Vec<String>,
+}
+
+impl SpoilerLog {
+    /// Parses a spoiler log sent by a client, returning an error message for the client if it's invalid.
+    fn parse(spoiler_log: &str) -> Result<Self, String> {
+        let spoiler_log = serde_json::from_str::<Self>(spoiler_log).map_err(|e| format!("failed to read spoiler log: {e}"))?;
+        let world_count = spoiler_log.settings.world_count;
+        let num_player_names = spoiler_log.settings.player_names.len();
+        if num_player_names > 0 && num_player_names != usize::from(world_count.get()) {
+            return Err(format!("invalid spoiler log: the seed has {world_count} worlds but {num_player_names} player names"))
+        }
+        Ok(spoiler_log)
+    }
+
+    fn seed_info(&self) -> SeedInfo {
+        SeedInfo {
+            file_hash: self.file_hash.to_vec(),
+            player_names: self.settings.player_names.clone(),
+        }
+    }
+}
+
+/// Hashes a room password on a blocking thread, since Argon2 is deliberately slow and would hold up other clients on the async executor.
+async fn hash_password(password: String) -> Result<String, SessionError> {
+    Ok(tokio::task::spawn_blocking(move || crate::hash_password(&password)).await??)
+}
+
+/// Checks a password against a room's password hash on a blocking thread, for the same reason as [`hash_password`]. Rooms without a password accept any password.
+async fn password_matches(room: &RwLock<Room>, password: String) -> Result<bool, SessionError> {
+    // copy the hash so the room isn't locked while verifying
+    let password_hash = room.read().await.password_hash.clone();
+    Ok(if let Some(password_hash) = password_hash {
+        tokio::task::spawn_blocking(move || crate::verify_password(&password_hash, &password)).await?
+    } else {
+        true
+    })
+}
+
+/// Writes the persistent parts of a room (everything except the connected clients) to the database.
+pub async fn save_room(db_pool: &SqlitePool, name: &str, room: &Room) -> Result<(), SessionError> {
+    let mut base_queue = Vec::default();
+    room.base_queue.write_sync(&mut base_queue)?;
+    let mut player_queues = Vec::default();
+    room.player_queues.write_sync(&mut player_queues)?;
+    let mut reservations = Vec::default();
+    room.reservations.write_sync(&mut reservations)?;
+    let mut queue_corrections = Vec::default();
+    room.queue_corrections.write_sync(&mut queue_corrections)?;
+    let seed = if let Some(ref seed) = room.seed {
+        let mut buf = Vec::default();
+        seed.write_sync(&mut buf)?;
+        Some(buf)
+    } else {
+        None
+    };
+    sqlx::query("INSERT INTO rooms (name, password_hash, base_queue, player_queues, last_activity, created, reservations, item_metadata, world_count, owner_token, locked, queue_corrections, seed) VALUES (?, ?, ?, ?, ?, ?, ?, TRUE, ?, ?, ?, ?, ?) ON CONFLICT (name) DO UPDATE SET password_hash = excluded.password_hash, base_queue = excluded.base_queue, player_queues = excluded.player_queues, last_activity = excluded.last_activity, reservations = excluded.reservations, item_metadata = TRUE, locked = excluded.locked, queue_corrections = excluded.queue_corrections")
+        .bind(name)
+        .bind(&room.password_hash)
+        .bind(base_queue)
+        .bind(player_queues)
+        .bind(room.last_activity)
+        .bind(room.created)
+        .bind(reservations)
+        .bind(room.world_count.map(NonZeroU8::get))
+        .bind(room.owner_token.as_ref().map(|token| &token[..]))
+        .bind(room.locked)
+        .bind(queue_corrections)
+        .bind(seed)
+        .execute(db_pool).await?;
+    Ok(())
+}
+
+/// Removes a room from the database and notifies lobby clients that it's gone.
+pub async fn delete_room(db_pool: &SqlitePool, room_tx: &mpsc::Sender<RoomListDelta>, name: String) -> sqlx::Result<()> {
+    sqlx::query("DELETE FROM rooms WHERE name = ?").bind(&name).execute(db_pool).await?;
+    room_tx.send(RoomListDelta::Delete(name)).await.expect("room list should be maintained indefinitely");
+    Ok(())
+}
+
+fn enter_room_message(room: &Room) -> ServerMessage {
+    let mut players = Vec::<Player>::default();
+    let mut num_unassigned_clients = 0;
+    for &(player, _) in room.clients.values() {
+        if let Some(player) = player {
+            players.insert(players.binary_search_by_key(&player.world, |p| p.world).expect_err("duplicate world number"), player);
+        } else {
+            num_unassigned_clients += 1;
+        }
+    }
+    ServerMessage::EnterRoom { players, num_unassigned_clients, chat_history: room.chat_history.clone() }
+}
+
+/// Notifies lobby clients of a change in the number of clients or players in a room.
+pub async fn update_room(room_tx: &mpsc::Sender<RoomListDelta>, name: &str, room: &Room) {
+    room_tx.send(RoomListDelta::Update { name: name.to_owned(), info: room.info() }).await.expect("room list should be maintained indefinitely");
+}
+
+/// The first byte sent by a client when starting a TLS session (the content type of a TLS handshake record).
+///
+/// Plaintext clients start by sending [`crate::NEGOTIATE_VERSION`] or their protocol version instead, which allows both kinds of clients to connect to the same port.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// How often clients are sent a [`ServerMessage::Ping`].
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Clients which haven't responded to a ping after this many heartbeat intervals are disconnected.
+const MAX_MISSED_HEARTBEATS: u8 = 3;
+
+/// The read half of a server's connection to a client.
+enum ClientReader {
+    /// A TCP connection, which may or may not be encrypted.
+    Stream(Box<dyn AsyncRead + Unpin + Send>),
+    /// A WebSocket connection. Text frames are decoded as JSON and binary frames using [`Protocol`], so clients can use either encoding.
+    WebSocket(Pin<Box<dyn Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Send>>),
+}
+
+impl ClientReader {
+    fn read<'a, T: Protocol + DeserializeOwned + Send + 'a>(&'a mut self) -> BoxFuture<'a, Result<T, async_proto::ReadError>> {
+        match self {
+            Self::Stream(reader) => T::read(reader),
+            Self::WebSocket(stream) => Box::pin(async move {
+                loop {
+                    match stream.try_next().await.map_err(io::Error::other)? {
+                        Some(tungstenite::Message::Binary(data)) => break T::read_sync(&mut &*data),
+                        Some(tungstenite::Message::Text(text)) => break serde_json::from_str(&text).map_err(|e| async_proto::ReadError::Custom(e.to_string())),
+                        // control frames are answered by tungstenite
+                        Some(tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) | tungstenite::Message::Frame(_)) => {}
+                        Some(tungstenite::Message::Close(_)) | None => break Err(async_proto::ReadError::EndOfStream),
+                    }
+                }
+            }),
+        }
+    }
+
+    /// Reads a [`LobbyClientMessage`], converting it from the encoding used by the given protocol version.
+    fn read_lobby_message(&mut self, version: u8) -> BoxFuture<'_, Result<LobbyClientMessage, async_proto::ReadError>> {
+        if version >= 2 {
+            self.read()
+        } else {
+            Box::pin(async move { Ok(self.read::<crate::v1::LobbyClientMessage>().await?.into()) })
+        }
+    }
+
+    /// Like [`ClientReader::read`], but takes ownership of the reader so the returned future can be kept around in a `select!` loop.
+    fn read_owned<T: Protocol + DeserializeOwned + Send + 'static>(mut self) -> BoxFuture<'static, Result<(Self, T), async_proto::ReadError>> {
+        Box::pin(async move {
+            let msg = self.read().await?;
+            Ok((self, msg))
+        })
+    }
+}
+
+/// Returns the TLS acceptor if TLS is enabled and the client has started a TLS session on a freshly accepted connection. Doesn't consume any data.
+async fn tls_acceptor_for(tls_acceptor: Option<TlsAcceptor>, socket: &TcpStream) -> io::Result<Option<TlsAcceptor>> {
+    Ok(if let Some(tls_acceptor) = tls_acceptor {
+        let mut first_byte = [0; 1];
+        socket.peek(&mut first_byte).await?;
+        (first_byte == [TLS_HANDSHAKE]).then_some(tls_acceptor)
+    } else {
+        None
+    })
+}
+
+/// Sets up a connection accepted on [`crate::PORT`].
+async fn tcp_connection(tls_acceptor: Option<TlsAcceptor>, socket: TcpStream) -> io::Result<(ClientReader, ClientWriter)> {
+    Ok(if let Some(tls_acceptor) = tls_acceptor_for(tls_acceptor, &socket).await? {
+        let (reader, writer) = io::split(tls_acceptor.accept(socket).await?);
+        (ClientReader::Stream(Box::new(reader)), ClientWriter::new(Transport::Tls(writer)))
+    } else {
+        let (reader, writer) = socket.into_split();
+        (ClientReader::Stream(Box::new(reader)), ClientWriter::new(Transport::Tcp(writer)))
+    })
+}
+
+/// Records whether a WebSocket client has connected to the `/json` path.
+struct JsonPathCallback<'a>(&'a mut bool);
+
+impl Callback for JsonPathCallback<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        *self.0 = request.uri().path() == "/json";
+        Ok(response)
+    }
+}
+
+/// Performs the WebSocket handshake on a connection accepted on the WebSocket port. Clients which connect to the `/json` path are sent JSON text frames instead of binary frames.
+async fn websocket_connection(tls_acceptor: Option<TlsAcceptor>, socket: TcpStream) -> io::Result<(ClientReader, ClientWriter)> {
+    let mut json = false;
+    let callback = JsonPathCallback(&mut json);
+    let (sink, stream) = if let Some(tls_acceptor) = tls_acceptor_for(tls_acceptor, &socket).await? {
+        let (sink, stream) = tokio_tungstenite::accept_hdr_async(tls_acceptor.accept(socket).await?, callback).await.map_err(io::Error::other)?.split();
+        (Box::pin(sink) as Pin<Box<dyn Sink<_, Error = _> + Send>>, Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>)
+    } else {
+        let (sink, stream) = tokio_tungstenite::accept_hdr_async(socket, callback).await.map_err(io::Error::other)?.split();
+        (Box::pin(sink) as Pin<Box<dyn Sink<_, Error = _> + Send>>, Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>)
+    };
+    Ok((ClientReader::WebSocket(stream), ClientWriter::new(Transport::WebSocket { sink, json })))
+}
+
+/// Reads the client's supported protocol versions and picks the newest one the server also supports, or returns `None` if there is none.
+///
+/// If this returns `None`, the client should be sent [`crate::VERSION`], which it will reject, before closing the connection.
+async fn negotiate_version(reader: &mut ClientReader) -> Result<Option<u8>, async_proto::ReadError> {
+    let supported = crate::MIN_SERVER_VERSION..=crate::VERSION;
+    let client_version = reader.read::<u8>().await?;
+    Ok(if client_version == crate::NEGOTIATE_VERSION {
+        let client_min = reader.read::<u8>().await?;
+        let client_max = reader.read::<u8>().await?;
+        let version = client_max.min(crate::VERSION);
+        (version >= client_min && supported.contains(&version)).then_some(version)
+    } else {
+        // version 1 clients don't negotiate and expect the server to use the version they sent
+        supported.contains(&client_version).then_some(client_version)
+    })
+}
+
+/// State shared by all client sessions.
+#[derive(Clone)]
+pub struct Server {
+    db_pool: SqlitePool,
+    rooms: ctrlflow::Handle<Rooms>,
+    join_limiter: Arc<Mutex<JoinLimiter>>,
+    restart_rx: watch::Receiver<Option<DateTime<Utc>>>,
+    /// Where rooms report their events for delivery to webhooks, if any are configured.
+    events: Option<mpsc::Sender<RoomEventReport>>,
+}
+
+impl Server {
+    /// `restart_rx` should be set to the time of the next restart shortly before the server shuts down, which warns connected clients and stops creation of new rooms.
+    pub fn new(db_pool: SqlitePool, rooms: ctrlflow::Handle<Rooms>, restart_rx: watch::Receiver<Option<DateTime<Utc>>>, events: Option<mpsc::Sender<RoomEventReport>>) -> Self {
+        Self {
+            join_limiter: Arc::default(),
+            db_pool, rooms, restart_rx, events,
+        }
+    }
+
+    /// Creates a server for playing over a local network. Its rooms are only kept in memory, so they're gone once the server is dropped.
+    pub async fn lan() -> Result<Self, MigrateError> {
+        let db_pool = SqlitePoolOptions::new()
+            // each connection to an in-memory database gets a separate database, so keep using the same connection
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:").await?;
+        MIGRATOR.run(&db_pool).await?;
+        // the sender is dropped immediately since a LAN server isn't restarted
+        let (_, restart_rx) = watch::channel(None);
+        Ok(Self::new(db_pool, ctrlflow::run(Rooms).await, restart_rx, None))
+    }
+
+    /// The rate limiter for failed attempts to join rooms, for checking room passwords outside of client sessions.
+    pub fn join_limiter(&self) -> Arc<Mutex<JoinLimiter>> {
+        Arc::clone(&self.join_limiter)
+    }
+}
+
+async fn client_session(server: Server, ip: IpAddr, socket_id: crate::SocketId, (mut reader, mut writer): (ClientReader, ClientWriter)) -> Result<(), SessionError> {
+    let Server { db_pool, rooms: rooms_handle, join_limiter, mut restart_rx, events } = server;
+    let version = negotiate_version(&mut reader).await?;
+    writer.write_handshake(&version.unwrap_or(crate::VERSION)).await?;
+    let version = version.ok_or(SessionError::VersionMismatch)?;
+    writer.version = version;
+    let writer = Arc::new(Mutex::new(writer));
+
+    macro_rules! error {
+        ($($msg:tt)*) => {{
+            let msg = format!($($msg)*);
+            writer.lock().await.write(&ServerMessage::Error(msg)).await?;
+            return Ok(())
+        }};
+    }
+
+    /// Like `error!`, but sends a [`ServerMessage::Warning`] and keeps the session going, for mistakes that shouldn't drop a player out of their room. Only for use in the room loop.
+    macro_rules! reject {
+        ($($msg:tt)*) => {{
+            let msg = format!($($msg)*);
+            writer.lock().await.write(&ServerMessage::Warning(msg)).await?;
+            continue
+        }};
+    }
+
+    let mut left_room = false;
+    loop {
+        let (mut room_tx, mut rooms, mut room_stream) = {
+            let (init, stream) = rooms_handle.stream().await;
+            let (tx, rooms) = init.clone();
+            let mut room_list = BTreeMap::default();
+            for (room_name, room) in &rooms {
+                room_list.insert(room_name.clone(), room.read().await.info());
+            }
+            if left_room {
+                writer.lock().await.write(&ServerMessage::LeftRoom(room_list)).await?;
+            } else if version < 2 {
+                // version 1 clients only know the room names
+                writer.lock().await.write_handshake(&room_list.into_keys().collect::<BTreeSet<_>>()).await?;
+            } else {
+                // finish handshake by sending room list (treated as a single packet)
+                writer.lock().await.write_handshake(&room_list).await?;
+            }
+            (tx, rooms, stream)
+        };
+        // the watch channel only notifies about changes after this point, so warn clients which connect (or leave a room) after the restart was announced here
+        let eta = *restart_rx.borrow_and_update();
+        if let Some(eta) = eta {
+            writer.lock().await.write(&ServerMessage::PrepareRestart(eta)).await?;
+        }
+        let (room_name, room, spectator) = {
+            let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+            let mut ping_sent = false;
+            let mut missed_heartbeats = 0;
+            'lobby: loop {
+                let mut read = reader.read_lobby_message(version);
+                break loop {
+                    select! {
+                        delta = room_stream.recv() => match delta {
+                            Ok(RoomListDelta::New { name, room }) => {
+                                let info = room.read().await.info();
+                                writer.lock().await.write(&ServerMessage::NewRoom(name.clone(), info)).await?;
+                                rooms.insert(name, room);
+                            }
+                            Ok(RoomListDelta::Update { name, info }) => if rooms.contains_key(&name) {
+                                writer.lock().await.write(&ServerMessage::UpdateRoom(name, info)).await?;
+                            },
+                            Ok(RoomListDelta::Delete(name)) => if rooms.remove(&name).is_some() {
+                                writer.lock().await.write(&ServerMessage::DeleteRoom(name)).await?;
+                            },
+                            Err(broadcast::error::RecvError::Closed) => unreachable!("room list should be maintained indefinitely"),
+                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                let (init, stream) = rooms_handle.stream().await;
+                                (room_tx, rooms) = init.clone();
+                                room_stream = stream;
+                            }
+                        },
+                        Ok(()) = restart_rx.changed() => {
+                            let eta = *restart_rx.borrow();
+                            if let Some(eta) = eta {
+                                writer.lock().await.write(&ServerMessage::PrepareRestart(eta)).await?;
+                            }
+                        },
+                        msg = &mut read => match msg? {
+                            LobbyClientMessage::Pong => {
+                                if ping_sent {
+                                    ping_sent = false;
+                                    missed_heartbeats = 0;
+                                }
+                                // start reading the next message
+                                continue 'lobby
+                            }
+                            LobbyClientMessage::JoinRoom { name, password, spoiler_log } => if let Some(room) = rooms.get(&name) {
+                                if room.read().await.locked { error!("room {name:?} is locked") }
+                                if let Err(msg) = join_limiter.lock().await.start_attempt(ip, &name) { error!("{msg}") }
+                                if password_matches(room, password).await? {
+                                    join_limiter.lock().await.succeeded(ip);
+                                } else {
+                                    join_limiter.lock().await.failed(ip, &name);
+                                    error!("wrong password for room {name:?}")
+                                }
+                                if let Some(spoiler_log) = spoiler_log {
+                                    let spoiler_log = match SpoilerLog::parse(&spoiler_log) {
+                                        Ok(spoiler_log) => spoiler_log,
+                                        Err(msg) => error!("{msg}"),
+                                    };
+                                    if room.read().await.seed.as_ref().map_or(false, |seed| *seed != spoiler_log.seed_info()) {
+                                        error!("this spoiler log is for a different seed than the one room {name:?} was created for")
+                                    }
+                                }
+                                if room.read().await.clients.len() >= usize::from(u8::MAX) { error!("room {name:?} is full") }
+                                {
+                                    let mut room = room.write().await;
+                                    room.add_client(socket_id, Arc::clone(&writer)).await;
+                                    room.last_activity = Utc::now();
+                                    save_room(&db_pool, &name, &room).await?;
+                                    update_room(&room_tx, &name, &room).await;
+                                    writer.lock().await.write(&enter_room_message(&room)).await?;
+                                }
+                                break (name, Arc::clone(room), false)
+                            } else {
+                                error!("there is no room named {name:?}")
+                            },
+                            LobbyClientMessage::Spectate { name, password } => if let Some(room) = rooms.get(&name) {
+                                if let Err(msg) = join_limiter.lock().await.start_attempt(ip, &name) { error!("{msg}") }
+                                if password_matches(room, password).await? {
+                                    join_limiter.lock().await.succeeded(ip);
+                                } else {
+                                    join_limiter.lock().await.failed(ip, &name);
+                                    error!("wrong password for room {name:?}")
+                                }
+                                if room.read().await.spectators.len() >= usize::from(u8::MAX) { error!("room {name:?} has too many spectators") }
+                                {
+                                    let mut room = room.write().await;
+                                    room.add_spectator(socket_id, Arc::clone(&writer));
+                                    writer.lock().await.write(&enter_room_message(&room)).await?;
+                                }
+                                break (name, Arc::clone(room), true)
+                            } else {
+                                error!("there is no room named {name:?}")
+                            },
+                            LobbyClientMessage::Resume { room: name, token } => if let Some(room) = rooms.get(&name) {
+                                {
+                                    let mut room = room.write().await;
+                                    if let Some(world) = room.resume_client(socket_id, Arc::clone(&writer), token).await {
+                                        writer.lock().await.write(&enter_room_message(&room)).await?;
+                                        room.load_player(socket_id, world).await;
+                                        room.last_activity = Utc::now();
+                                        save_room(&db_pool, &name, &room).await?;
+                                        update_room(&room_tx, &name, &room).await;
+                                    } else {
+                                        error!("your session in room {name:?} has expired, please rejoin")
+                                    }
+                                }
+                                break (name, Arc::clone(room), false)
+                            } else {
+                                error!("room {name:?} no longer exists")
+                            },
+                            LobbyClientMessage::CreateRoom { name, password, spoiler_log } => {
+                                if restart_rx.borrow().is_some() { error!("the server is about to restart, please try again later") }
+                                if name.is_empty() { error!("room name must not be empty") }
+                                if name.chars().count() >= 64 { error!("room name too long (maximum 64 characters)") }
+                                if name.contains('\0') { error!("room name must not contain null characters") }
+                                if password.chars().count() >= 64 { error!("room password too long (maximum 64 characters)") }
+                                if password.contains('\0') { error!("room password must not contain null characters") }
+                                if rooms.contains_key(&name) { error!("a room with this name already exists") }
+                                let (world_count, seed) = if let Some(spoiler_log) = spoiler_log {
+                                    match SpoilerLog::parse(&spoiler_log) {
+                                        Ok(spoiler_log) => (Some(spoiler_log.settings.world_count), Some(spoiler_log.seed_info())),
+                                        Err(msg) => error!("{msg}"),
+                                    }
+                                } else {
+                                    (None, None)
+                                };
+                                let mut clients = HashMap::default();
+                                clients.insert(socket_id, (None, Arc::clone(&writer)));
+                                let mut owner_token = [0; 16];
+                                OsRng.fill_bytes(&mut owner_token);
+                                let room = Room {
+                                    password_hash: if password.is_empty() { None } else { Some(hash_password(password).await?) },
+                                    clients,
+                                    base_queue: Vec::default(),
+                                    player_queues: HashMap::default(),
+                                    last_activity: Utc::now(),
+                                    created: Utc::now(),
+                                    reservations: HashMap::default(),
+                                    spectators: HashMap::default(),
+                                    world_count, seed,
+                                    owner_token: Some(owner_token),
+                                    owners: HashSet::from([socket_id]),
+                                    locked: false,
+                                    queue_corrections: Vec::default(),
+                                    events: events.clone().map(|tx| EventSender::new(name.clone(), tx)),
+                                    chat_history: Vec::default(),
+                                };
+                                room.report_event(RoomEvent::Created);
+                                save_room(&db_pool, &name, &room).await?;
+                                let room = Arc::new(RwLock::new(room));
+                                room_tx.send(RoomListDelta::New { name: name.clone(), room: Arc::clone(&room) }).await.expect("room list should be maintained indefinitely");
+                                writer.lock().await.write(&ServerMessage::EnterRoom {
+                                    players: Vec::default(),
+                                    num_unassigned_clients: 1,
+                                    chat_history: Vec::default(),
+                                }).await?;
+                                writer.lock().await.write(&ServerMessage::OwnerToken(owner_token)).await?;
+                                break (name, room, false)
+                            }
+                        },
+                        // version 1 clients don't know about pings
+                        _ = heartbeat.tick(), if version >= 2 => if ping_sent {
+                            missed_heartbeats += 1;
+                            if missed_heartbeats >= MAX_MISSED_HEARTBEATS { return Err(SessionError::MissedHeartbeats) }
+                        } else {
+                            ping_sent = true;
+                            writer.lock().await.write(&ServerMessage::Ping).await?;
+                        },
+                    }
+                }
+            }
+        };
+        let mut read = reader.read_owned::<RoomClientMessage>();
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+        let mut ping_sent = None;
+        let mut missed_heartbeats = 0;
+        reader = loop {
+            select! {
+                res = &mut read => {
+                    let (reader, msg) = res?;
+                    // hash a new password before locking the room, so the other clients aren't held up by Argon2
+                    let new_password_hash = match msg {
+                        RoomClientMessage::SetPassword(ref password) if !password.is_empty() && password.chars().count() < 64 && !password.contains('\0') => Some(hash_password(password.clone()).await?),
+                        _ => None,
+                    };
+                    let mut room = room.write().await;
+                    if !room.has_client(socket_id) {
+                        // disconnected by an admin
+                        return Ok(())
+                    }
+                    if let RoomClientMessage::LeaveRoom = msg {
+                        // older clients can't be sent ServerMessage::LeftRoom, so they have no way to know they're back in the lobby
+                        if version < 2 { error!("leaving a room requires protocol version 2 or later") }
+                        room.leave(socket_id).await;
+                        room.last_activity = Utc::now();
+                        save_room(&db_pool, &room_name, &room).await?;
+                        update_room(&room_tx, &room_name, &room).await;
+                        break reader
+                    }
+                    read = reader.read_owned::<RoomClientMessage>();
+                    let info = room.info();
+                    let corrector = Corrector::Owner(room.clients.get(&socket_id).and_then(|&(player, _)| player).map(|player| player.world));
+                    match msg {
+                        RoomClientMessage::LeaveRoom => unreachable!("handled above"),
+                        RoomClientMessage::Pong => {
+                            if let Some(ping_sent) = ping_sent.take() {
+                                missed_heartbeats = 0;
+                                room.set_latency(socket_id, Instant::now() - ping_sent).await;
+                            }
+                            // heartbeats don't count as room activity
+                            continue
+                        }
+                        RoomClientMessage::ClaimOwnership(token) => if room.owner_token == Some(token) {
+                            room.add_owner(socket_id).await;
+                        } else {
+                            reject!("invalid owner token for room {room_name:?}")
+                        },
+                        RoomClientMessage::AddOwner(_) | RoomClientMessage::KickPlayer(_) | RoomClientMessage::UnassignWorld(_) | RoomClientMessage::SetPassword(_) | RoomClientMessage::SetLocked(_) |
+                        RoomClientMessage::ResendItem { .. } | RoomClientMessage::RemoveItem { .. } | RoomClientMessage::InjectItem { .. } if !room.owners.contains(&socket_id) => reject!("only the room's owners can do this"),
+                        RoomClientMessage::AddOwner(world) => if let Some(client_id) = room.client_for_world(world) {
+                            room.add_owner(client_id).await;
+                        } else {
+                            reject!("world {world} has not been claimed by a connected player")
+                        },
+                        RoomClientMessage::KickPlayer(world) => if let Some(client_id) = room.client_for_world(world) {
+                            room.disconnect(client_id, "you have been kicked from this room by its owner").await;
+                        } else {
+                            reject!("world {world} has not been claimed by a connected player")
+                        },
+                        RoomClientMessage::UnassignWorld(world) => if !room.unassign_world(world).await {
+                            reject!("world {world} is not assigned")
+                        },
+                        RoomClientMessage::SetPassword(password) => {
+                            if password.chars().count() >= 64 { reject!("room password too long (maximum 64 characters)") }
+                            if password.contains('\0') { reject!("room password must not contain null characters") }
+                            room.password_hash = new_password_hash;
+                        }
+                        RoomClientMessage::SetLocked(locked) => room.locked = locked,
+                        RoomClientMessage::ResendItem { world, source, key } => if !room.resend_item(corrector, world, source, key).await {
+                            reject!("world {world} has not received an item from world {source} at location key 0x{key:08x}")
+                        },
+                        RoomClientMessage::RemoveItem { world, source, key } => if !room.remove_item(corrector, world, source, key).await {
+                            reject!("world {world} has not received an item from world {source} at location key 0x{key:08x}")
+                        },
+                        RoomClientMessage::InjectItem { world, source, kind } => {
+                            if let Some(world_count) = room.world_count {
+                                if world > world_count { reject!("world {world} does not exist in this seed, which has {world_count} worlds") }
+                            }
+                            room.inject_item(corrector, world, source, kind).await;
+                        }
+                        _ if spectator => error!("spectators can't claim worlds or send items"),
+                        RoomClientMessage::PlayerId(id) => {
+                            if let Some(world_count) = room.world_count {
+                                if id > world_count { error!("world {id} does not exist in this seed, which has {world_count} worlds") }
+                            }
+                            if !room.load_player(socket_id, id).await {
+                                error!("world {id} is already taken")
+                            }
+                        }
+                        RoomClientMessage::ResetPlayerId => room.unload_player(socket_id).await,
+                        RoomClientMessage::PlayerName(name) => if !room.set_player_name(socket_id, name).await {
+                            error!("please claim a world before setting your player name")
+                        },
+                        RoomClientMessage::SendItem { key, kind, target_world } => {
+                            if let Some(world_count) = room.world_count {
+                                if target_world > world_count {
+                                    // the item can never be received, so don't queue it, but keep the session going in case this was a glitch
+                                    eprintln!("{} room {room_name:?}: client {socket_id} sent item 0x{kind:04x} (location key 0x{key:08x}) to world {target_world}, but the seed only has {world_count} worlds", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+                                    writer.lock().await.write(&ServerMessage::Warning(format!("you sent an item to world {target_world}, but this room's seed only has {world_count} worlds, are you playing the right seed?"))).await?;
+                                    continue
+                                }
+                            }
+                            if !room.queue_item(socket_id, key, kind, target_world).await {
+                                error!("please claim a world before sending items")
+                            }
+                        }
+                        RoomClientMessage::ChatMessage(text) => {
+                            if let Some(msg) = crate::chat_message_error(&text) { reject!("{msg}") }
+                            if !room.send_chat_message(socket_id, text.trim().to_owned()).await {
+                                reject!("please claim a world before sending chat messages")
+                            }
+                        }
+                    }
+                    room.last_activity = Utc::now();
+                    save_room(&db_pool, &room_name, &room).await?;
+                    if room.info() != info {
+                        update_room(&room_tx, &room_name, &room).await;
+                    }
+                }
+                // version 1 clients don't know about pings
+                _ = heartbeat.tick(), if version >= 2 => if ping_sent.is_some() {
+                    missed_heartbeats += 1;
+                    if missed_heartbeats >= MAX_MISSED_HEARTBEATS { return Err(SessionError::MissedHeartbeats) }
+                } else {
+                    ping_sent = Some(Instant::now());
+                    writer.lock().await.write(&ServerMessage::Ping).await?;
+                },
+            }
+        };
+        left_room = true;
+    }
+}
+
+/// Accepts connections on the given listener, which is either the TCP listener on [`crate::PORT`] or the WebSocket listener.
+pub async fn accept_connections(listener: TcpListener, server: Server, tls_acceptor: Option<TlsAcceptor>, websocket: bool) -> io::Result<Never> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let socket_id = crate::socket_id(&socket);
+        let server = server.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let res = async {
+                let ip = socket.peer_addr()?.ip();
+                let connection = if websocket {
+                    websocket_connection(tls_acceptor, socket).await?
+                } else {
+                    tcp_connection(tls_acceptor, socket).await?
+                };
+                client_session(server.clone(), ip, socket_id, connection).await
+            }.await;
+            if let Err(e) = res {
+                eprintln!("{} error in client session: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+            }
+            let Server { db_pool, rooms, .. } = server;
+            let (room_tx, room_list) = rooms.state().await.clone();
+            for (room_name, room) in &room_list {
+                if room.read().await.has_client(socket_id) {
+                    let mut room = room.write().await;
+                    room.remove_client(socket_id).await;
+                    room.last_activity = Utc::now();
+                    if let Err(e) = save_room(&db_pool, room_name, &room).await {
+                        eprintln!("{} error saving room: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+                    }
+                    update_room(&room_tx, room_name, &room).await;
+                }
+            }
+        });
+    }
+}
+
+/// Checks whether a discovery query could have come from the local network. Source addresses of UDP packets can be spoofed, so replies to anything else could be used to flood third parties.
+fn is_lan_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => if let Some(ip) = ip.to_ipv4_mapped() {
+            is_lan_address(IpAddr::V4(ip))
+        } else {
+            // unique local (fc00::/7) or link-local (fe80::/10)
+            ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80
+        },
+    }
+}
+
+/// Replies to [`discovery`](crate::discovery) queries from clients on the local network with the server's room list.
+pub async fn answer_discovery(server: Server) -> io::Result<Never> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, discovery::PORT)).await?;
+    let mut buf = [0; 16];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        if !is_lan_address(addr.ip()) || !discovery::is_query(&buf[..len]) { continue }
+        let mut room_list = BTreeMap::default();
+        for (room_name, room) in &server.rooms.state().await.1 {
+            room_list.insert(room_name.clone(), room.read().await.info());
+        }
+        // the client only waits briefly for replies, so there's nothing useful to do if this fails
+        let _ = socket.send_to(&discovery::reply(&room_list), addr).await;
+    }
+}
+
+/// The key for the list of rooms on a server, which is maintained using [`ctrlflow`].
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Rooms;
+
+/// A change to the list of rooms on a server.
+#[derive(Debug, Clone)]
+pub enum RoomListDelta {
+    New {
+        name: String,
+        room: Arc<RwLock<Room>>,
+    },
+    Update {
+        name: String,
+        info: RoomInfo,
+    },
+    Delete(String),
+}
+
+impl<T> ctrlflow::Delta<(T, HashMap<String, Arc<RwLock<Room>>>)> for RoomListDelta {
+    fn apply(&self, state: &mut (T, HashMap<String, Arc<RwLock<Room>>>)) {
+        match self {
+            Self::New { name, room } => { state.1.insert(name.clone(), room.clone()); }
+            Self::Update { .. } => {}
+            Self::Delete(name) => { state.1.remove(name); }
+        }
+    }
+}
+
+impl ctrlflow::Key for Rooms {
+    type State = (mpsc::Sender<RoomListDelta>, HashMap<String, Arc<RwLock<Room>>>);
+    type Delta = RoomListDelta;
+
+    fn maintain(self, _: ctrlflow::RunnerInternal<Self>) -> Pin<Box<dyn Future<Output = (Self::State, Pin<Box<dyn Stream<Item = Self::Delta> + Send>>)> + Send>> {
+        Box::pin(async move {
+            let (tx, rx) = mpsc::channel(64);
+            ((tx, HashMap::default()), ReceiverStream::new(rx).boxed())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::io::Cursor,
+        crate::{
+            MIN_SERVER_VERSION,
+            NEGOTIATE_VERSION,
+            VERSION,
+        },
+        super::*,
+    };
+
+    async fn negotiate(handshake: &[u8]) -> Option<u8> {
+        let mut reader = ClientReader::Stream(Box::new(Cursor::new(handshake.to_vec())));
+        negotiate_version(&mut reader).await.expect("failed to read handshake")
+    }
+
+    #[tokio::test]
+    async fn legacy_client() {
+        assert_eq!(negotiate(&[1]).await, Some(1));
+        assert_eq!(negotiate(&[VERSION + 1]).await, None);
+    }
+
+    #[tokio::test]
+    async fn overlapping_ranges() {
+        assert_eq!(negotiate(&[NEGOTIATE_VERSION, VERSION, VERSION]).await, Some(VERSION));
+        // client is newer than the server
+        assert_eq!(negotiate(&[NEGOTIATE_VERSION, MIN_SERVER_VERSION, u8::MAX]).await, Some(VERSION));
+        // client is older than the server
+        assert_eq!(negotiate(&[NEGOTIATE_VERSION, 1, MIN_SERVER_VERSION]).await, Some(MIN_SERVER_VERSION));
+    }
+
+    #[tokio::test]
+    async fn disjoint_ranges() {
+        assert_eq!(negotiate(&[NEGOTIATE_VERSION, VERSION + 1, u8::MAX]).await, None);
+    }
+
+    #[test]
+    fn lan_addresses() {
+        for ip in ["192.168.1.2", "10.0.0.1", "169.254.3.4", "127.0.0.1", "::ffff:172.16.0.1", "fd12::1", "fe80::1"] {
+            assert!(is_lan_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "::ffff:1.1.1.1", "2001:db8::1"] {
+            assert!(!is_lan_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn truncated_discovery_reply() {
+        let rooms = (0..100).map(|i| (format!("room {i:03}"), RoomInfo {
+            created: Utc::now(),
+            num_players: 0,
+            num_clients: 1,
+            password_protected: false,
+            locked: false,
+        })).collect::<BTreeMap<_, _>>();
+        let reply = discovery::reply(&rooms);
+        assert!(reply.len() <= discovery::MAX_REPLY_LEN);
+        assert!(reply.len() > discovery::MAX_REPLY_LEN - 100);
+    }
+}
@@ -0,0 +1,125 @@
+//! Rate limiting of failed attempts to join rooms, to protect room passwords against brute-force attacks.
+
+use {
+    std::{
+        collections::{
+            HashMap,
+            HashSet,
+        },
+        net::{
+            IpAddr,
+            Ipv6Addr,
+        },
+    },
+    chrono::{
+        Duration,
+        prelude::*,
+    },
+};
+
+/// Number of failed joins from the same IP address after which further attempts are locked out.
+const IP_ATTEMPTS: u32 = 5;
+/// Number of failed joins to the same room after which further attempts from IP addresses which have failed to join it are locked out.
+///
+/// This catches attacks spread across many IP addresses. Players who haven't entered a wrong password for the room can still join it during the lockout.
+const ROOM_ATTEMPTS: u32 = 20;
+/// The length of the first lockout. Each further failed attempt doubles this, up to [`MAX_LOCKOUT_SECS`].
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+/// Failures are forgotten after this long without another failure.
+const RESET_SECS: i64 = 60 * 60;
+
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+impl Failures {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self { count: 0, last: now }
+    }
+
+    fn record(&mut self, now: DateTime<Utc>) {
+        self.count += 1;
+        self.last = now;
+    }
+
+    /// Returns the end of the current lockout, if any.
+    fn locked_until(&self, max_attempts: u32, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let excess = self.count.checked_sub(max_attempts)?;
+        let lockout = BASE_LOCKOUT_SECS.saturating_mul(1 << excess.min(16)).min(MAX_LOCKOUT_SECS);
+        Some(self.last + Duration::seconds(lockout)).filter(|&until| until > now)
+    }
+}
+
+/// Failed joins to a room, along with the IP addresses they came from.
+struct RoomFailures {
+    failures: Failures,
+    ips: HashSet<IpAddr>,
+}
+
+/// Clients on the same IPv6 /64 network are usually the same host, so they're rate limited together.
+fn ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => if let Some(ip) = ip.to_ipv4_mapped() {
+            IpAddr::V4(ip)
+        } else {
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64)))
+        },
+    }
+}
+
+fn format_wait(until: DateTime<Utc>) -> String {
+    let secs = (until - Utc::now()).num_seconds().max(1);
+    if secs < 120 {
+        format!("{secs} second{}", if secs == 1 { "" } else { "s" })
+    } else {
+        format!("{} minutes", (secs + 59) / 60)
+    }
+}
+
+/// Shared by all of a server's listeners, including `ootrmwd`'s HTTP API, so each of them can't be used to get around the others' limits.
+///
+/// Each password check must be wrapped in [`JoinLimiter::start_attempt`] and either [`JoinLimiter::succeeded`] or [`JoinLimiter::failed`].
+#[derive(Default)]
+pub struct JoinLimiter {
+    ips: HashMap<IpAddr, Failures>,
+    rooms: HashMap<String, RoomFailures>,
+}
+
+impl JoinLimiter {
+    /// Returns an error message to send to the client if attempts to join the given room from the given IP address are currently locked out.
+    ///
+    /// Otherwise, the attempt is counted as a failure right away, so that parallel connections can't all get past the limit while their passwords are being verified. [`JoinLimiter::succeeded`] takes it back.
+    pub fn start_attempt(&mut self, ip: IpAddr, room: &str) -> Result<(), String> {
+        let now = Utc::now();
+        let reset = Duration::seconds(RESET_SECS);
+        self.ips.retain(|_, failures| now - failures.last < reset);
+        self.rooms.retain(|_, room| now - room.failures.last < reset);
+        let ip = ip_key(ip);
+        if let Some(until) = self.ips.get(&ip).and_then(|failures| failures.locked_until(IP_ATTEMPTS, now)) {
+            return Err(format!("too many failed attempts to join a room, please try again in {}", format_wait(until)))
+        }
+        if let Some(until) = self.rooms.get(room).filter(|failures| failures.ips.contains(&ip)).and_then(|failures| failures.failures.locked_until(ROOM_ATTEMPTS, now)) {
+            return Err(format!("too many failed attempts to join room {room:?}, please try again in {}", format_wait(until)))
+        }
+        self.ips.entry(ip).or_insert_with(|| Failures::new(now)).record(now);
+        Ok(())
+    }
+
+    /// Takes back the failure counted by [`JoinLimiter::start_attempt`] after the client has entered the correct password.
+    pub fn succeeded(&mut self, ip: IpAddr) {
+        if let Some(failures) = self.ips.get_mut(&ip_key(ip)) {
+            failures.count = failures.count.saturating_sub(1);
+        }
+    }
+
+    /// Counts a wrong password towards the room-wide limit. The attempt has already been counted towards the per-IP limit by [`JoinLimiter::start_attempt`].
+    pub fn failed(&mut self, ip: IpAddr, room: &str) {
+        let now = Utc::now();
+        let room = self.rooms.entry(room.to_owned()).or_insert_with(|| RoomFailures { failures: Failures::new(now), ips: HashSet::default() });
+        room.failures.record(now);
+        room.ips.insert(ip_key(ip));
+    }
+}
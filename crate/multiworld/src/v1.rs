@@ -0,0 +1,158 @@
+//! Messages as encoded in protocol version 1, which is spoken by the released clients. Room client messages from this version are a subset of the current ones, so only lobby client messages and server messages need to be converted.
+
+use {
+    std::num::NonZeroU8,
+    async_proto::Protocol,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    crate::Player,
+};
+
+/// [`crate::LobbyClientMessage`] as of version 1. See there for documentation.
+#[derive(Protocol, Deserialize)]
+pub(crate) enum LobbyClientMessage {
+    JoinRoom {
+        name: String,
+        password: String,
+    },
+    CreateRoom {
+        name: String,
+        password: String,
+    },
+}
+
+impl From<LobbyClientMessage> for crate::LobbyClientMessage {
+    fn from(msg: LobbyClientMessage) -> Self {
+        match msg {
+            LobbyClientMessage::JoinRoom { name, password } => Self::JoinRoom { name, password, spoiler_log: None },
+            LobbyClientMessage::CreateRoom { name, password } => Self::CreateRoom { name, password, spoiler_log: None },
+        }
+    }
+}
+
+/// [`crate::ServerMessage`] as of version 1. See there for documentation.
+#[derive(Protocol, Serialize)]
+pub(crate) enum ServerMessage {
+    Error(String),
+    NewRoom(String),
+    EnterRoom {
+        players: Vec<Player>,
+        num_unassigned_clients: u8,
+    },
+    PlayerId(NonZeroU8),
+    ResetPlayerId(NonZeroU8),
+    ClientConnected,
+    PlayerDisconnected(NonZeroU8),
+    UnregisteredClientDisconnected,
+    PlayerName(NonZeroU8, [u8; 8]),
+    ItemQueue(Vec<u16>),
+    GetItem(u16),
+}
+
+impl ServerMessage {
+    /// Converts a message to its version 1 encoding. Returns `None` for messages which version 1 clients wouldn't understand. Most of these are only sent in response to messages which version 1 clients don't send, or are purely informational.
+    pub(crate) fn from_current(msg: &crate::ServerMessage) -> Option<Self> {
+        Some(match *msg {
+            crate::ServerMessage::Error(ref msg) => Self::Error(msg.clone()),
+            crate::ServerMessage::NewRoom(ref name, _) => Self::NewRoom(name.clone()),
+            crate::ServerMessage::EnterRoom { ref players, num_unassigned_clients, chat_history: _ } => Self::EnterRoom { players: players.clone(), num_unassigned_clients },
+            crate::ServerMessage::PlayerId(world) => Self::PlayerId(world),
+            crate::ServerMessage::ResetPlayerId(world) => Self::ResetPlayerId(world),
+            crate::ServerMessage::ClientConnected => Self::ClientConnected,
+            crate::ServerMessage::PlayerDisconnected(world) => Self::PlayerDisconnected(world),
+            crate::ServerMessage::UnregisteredClientDisconnected => Self::UnregisteredClientDisconnected,
+            crate::ServerMessage::PlayerName(world, name) => Self::PlayerName(world, name),
+            crate::ServerMessage::ItemQueue(ref queue) => Self::ItemQueue(queue.clone()),
+            crate::ServerMessage::GetItem(item) => Self::GetItem(item),
+            crate::ServerMessage::DeleteRoom(_) |
+            crate::ServerMessage::PrepareRestart(_) |
+            crate::ServerMessage::UpdateRoom(_, _) |
+            crate::ServerMessage::ResumptionToken(_) |
+            crate::ServerMessage::Ping |
+            crate::ServerMessage::PlayerLatency(_, _) |
+            crate::ServerMessage::ItemSent { .. } |
+            crate::ServerMessage::OwnerToken(_) |
+            crate::ServerMessage::ChatMessage(_) |
+            crate::ServerMessage::LeftRoom(_) |
+            crate::ServerMessage::Warning(_) => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::collections::BTreeMap,
+        chrono::prelude::*,
+        crate::{
+            ChatMessage,
+            RoomInfo,
+        },
+        super::*,
+    };
+
+    fn chat_message() -> ChatMessage {
+        ChatMessage {
+            time: Utc::now(),
+            sender: Player::new(NonZeroU8::new(1).unwrap()),
+            text: "hello".to_owned(),
+        }
+    }
+
+    #[test]
+    fn drops_new_messages() {
+        assert!(ServerMessage::from_current(&crate::ServerMessage::Ping).is_none());
+        assert!(ServerMessage::from_current(&crate::ServerMessage::OwnerToken([0; 16])).is_none());
+        assert!(ServerMessage::from_current(&crate::ServerMessage::ChatMessage(chat_message())).is_none());
+        assert!(ServerMessage::from_current(&crate::ServerMessage::LeftRoom(BTreeMap::default())).is_none());
+    }
+
+    #[test]
+    fn drops_chat_history() {
+        let msg = ServerMessage::from_current(&crate::ServerMessage::EnterRoom {
+            players: vec![Player::new(NonZeroU8::new(2).unwrap())],
+            num_unassigned_clients: 1,
+            chat_history: vec![chat_message()],
+        });
+        assert!(matches!(msg, Some(ServerMessage::EnterRoom { ref players, num_unassigned_clients: 1 }) if players.len() == 1 && players[0].world.get() == 2));
+    }
+
+    #[test]
+    fn drops_room_info() {
+        let msg = ServerMessage::from_current(&crate::ServerMessage::NewRoom("room".to_owned(), RoomInfo {
+            created: Utc::now(),
+            num_players: 0,
+            num_clients: 1,
+            password_protected: false,
+            locked: false,
+        }));
+        assert!(matches!(msg, Some(ServerMessage::NewRoom(ref name)) if name == "room"));
+    }
+
+    #[test]
+    fn same_encoding() {
+        // messages which exist in both versions must be encoded the same way, since clients decode them using their own definitions
+        for msg in [
+            crate::ServerMessage::Error("error".to_owned()),
+            crate::ServerMessage::PlayerName(NonZeroU8::new(1).unwrap(), Player::DEFAULT_NAME),
+            crate::ServerMessage::ItemQueue(vec![0xca, 0x3b]),
+            crate::ServerMessage::GetItem(0xca),
+        ] {
+            let mut current = Vec::default();
+            msg.write_sync(&mut current).unwrap();
+            let mut v1 = Vec::default();
+            ServerMessage::from_current(&msg).unwrap().write_sync(&mut v1).unwrap();
+            assert_eq!(current, v1);
+        }
+    }
+
+    #[test]
+    fn create_room_without_spoiler_log() {
+        let mut buf = Vec::default();
+        LobbyClientMessage::CreateRoom { name: "room".to_owned(), password: String::default() }.write_sync(&mut buf).unwrap();
+        let msg = crate::LobbyClientMessage::from(LobbyClientMessage::read_sync(&mut &*buf).unwrap());
+        assert!(matches!(msg, crate::LobbyClientMessage::CreateRoom { ref name, ref password, spoiler_log: None } if name == "room" && password.is_empty()));
+    }
+}
@@ -0,0 +1,346 @@
+//! The admin interface, a Unix domain socket on which the server accepts [`Command`]s from `ootrmwd admin`.
+
+use {
+    std::{
+        collections::BTreeMap,
+        num::{
+            NonZeroU8,
+            ParseIntError,
+        },
+        path::Path,
+    },
+    async_proto::Protocol,
+    chrono::prelude::*,
+    itertools::Itertools as _,
+    serde::Serialize,
+    sqlx::SqlitePool,
+    tokio::{
+        fs,
+        io,
+        net::{
+            UnixListener,
+            UnixStream,
+        },
+    },
+    multiworld::{
+        Corrector,
+        Item,
+        Player,
+        QueueCorrection,
+        QueueCorrectionKind,
+        SocketId,
+        render_filename,
+        server::{
+            Rooms,
+            delete_room,
+            save_room,
+            update_room,
+        },
+    },
+    crate::Error,
+};
+
+#[derive(clap::Subcommand, Protocol)]
+pub(crate) enum Command {
+    /// Lists all rooms along with their connected clients and spectators.
+    Rooms,
+    /// Disconnects the client with the given socket ID from its room.
+    Kick {
+        socket_id: SocketId,
+    },
+    /// Disconnects all clients from a room and deletes it.
+    DeleteRoom {
+        name: String,
+    },
+    /// Shows the item queues of a room.
+    Queues {
+        room: String,
+    },
+    /// Prints every item sent in a room, in JSON format unless `--csv` is given.
+    ItemLog {
+        room: String,
+        #[clap(long)]
+        csv: bool,
+    },
+    /// Adds another copy of an item to the end of a world's queue, e.g. if it was lost in a game crash.
+    ResendItem {
+        room: String,
+        world: NonZeroU8,
+        /// The world that originally sent the item.
+        source: NonZeroU8,
+        /// The location key in hexadecimal, as shown by the `queues` command.
+        #[clap(parse(try_from_str = parse_hex_u32))]
+        key: u32,
+    },
+    /// Removes an item which hasn't been received yet from a world's queue.
+    RemoveItem {
+        room: String,
+        world: NonZeroU8,
+        /// The world that sent the item.
+        source: NonZeroU8,
+        /// The location key in hexadecimal, as shown by the `queues` command.
+        #[clap(parse(try_from_str = parse_hex_u32))]
+        key: u32,
+    },
+    /// Adds a new item to the end of a world's queue.
+    InjectItem {
+        room: String,
+        world: NonZeroU8,
+        /// The world that will be shown as the sender of the item.
+        source: NonZeroU8,
+        /// The item kind in hexadecimal, as shown by the `queues` command.
+        #[clap(parse(try_from_str = parse_hex_u16))]
+        kind: u16,
+    },
+    /// Shows the manual changes that have been made to the item queues of a room.
+    Corrections {
+        room: String,
+    },
+}
+
+fn parse_hex_u32(s: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+fn parse_hex_u16(s: &str) -> Result<u16, ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+#[derive(Protocol)]
+enum Response {
+    Ok,
+    Error(String),
+    Rooms(BTreeMap<String, RoomConnections>),
+    Queues {
+        base_queue: Vec<Item>,
+        player_queues: BTreeMap<NonZeroU8, Vec<Item>>,
+    },
+    ItemLog {
+        items: Vec<Item>,
+        csv: bool,
+    },
+    Corrections(Vec<QueueCorrection>),
+}
+
+/// A row of the exported item log.
+#[derive(Serialize)]
+struct ItemLogEntry {
+    time: DateTime<Utc>,
+    source: NonZeroU8,
+    target: NonZeroU8,
+    key: u32,
+    kind: u16,
+}
+
+impl From<Item> for ItemLogEntry {
+    fn from(Item { source, target, key, kind, time }: Item) -> Self {
+        Self { time, source, target, key, kind }
+    }
+}
+
+#[derive(Protocol)]
+struct RoomConnections {
+    clients: Vec<(SocketId, Option<Player>)>,
+    spectators: Vec<SocketId>,
+    owners: Vec<SocketId>,
+    locked: bool,
+}
+
+async fn handle(db_pool: &SqlitePool, rooms: &ctrlflow::Handle<Rooms>, command: Command) -> Result<Response, Error> {
+    Ok(match command {
+        Command::Rooms => {
+            let mut list = BTreeMap::default();
+            for (name, room) in &rooms.state().await.1 {
+                let room = room.read().await;
+                list.insert(name.clone(), RoomConnections {
+                    clients: room.clients.iter().map(|(&socket_id, &(player, _))| (socket_id, player)).sorted_by_key(|&(socket_id, _)| socket_id).collect(),
+                    spectators: room.spectators.keys().copied().sorted().collect(),
+                    owners: room.owners.iter().copied().sorted().collect(),
+                    locked: room.locked,
+                });
+            }
+            Response::Rooms(list)
+        }
+        Command::Kick { socket_id } => {
+            let mut found = false;
+            let (room_tx, room_list) = rooms.state().await.clone();
+            for (name, room) in &room_list {
+                let mut room = room.write().await;
+                if room.has_client(socket_id) {
+                    room.disconnect(socket_id, "you have been kicked from this room by a server admin").await;
+                    update_room(&room_tx, name, &room).await;
+                    found = true;
+                }
+            }
+            if found { Response::Ok } else { Response::Error(format!("no client with socket ID {socket_id} is in a room")) }
+        }
+        Command::DeleteRoom { name } => {
+            let (room_tx, room_list) = rooms.state().await.clone();
+            if let Some(room) = room_list.get(&name) {
+                let mut room = room.write().await;
+                for socket_id in room.clients.keys().chain(room.spectators.keys()).copied().collect_vec() {
+                    room.disconnect(socket_id, "this room has been deleted by a server admin").await;
+                }
+                drop(room);
+                delete_room(db_pool, &room_tx, name).await?;
+                Response::Ok
+            } else {
+                Response::Error(format!("there is no room named {name:?}"))
+            }
+        }
+        Command::Queues { room } => if let Some(room) = rooms.state().await.1.get(&room) {
+            let room = room.read().await;
+            Response::Queues {
+                base_queue: room.base_queue.clone(),
+                player_queues: room.player_queues.iter().map(|(&world, queue)| (world, queue.clone())).collect(),
+            }
+        } else {
+            Response::Error(format!("there is no room named {room:?}"))
+        },
+        Command::ItemLog { room, csv } => if let Some(room) = rooms.state().await.1.get(&room) {
+            Response::ItemLog {
+                items: room.read().await.item_log(),
+                csv,
+            }
+        } else {
+            Response::Error(format!("there is no room named {room:?}"))
+        },
+        Command::ResendItem { room: name, world, source, key } => if let Some(room) = rooms.state().await.1.get(&name) {
+            let mut room = room.write().await;
+            if let Some(world_count) = room.world_count.filter(|&world_count| world > world_count) {
+                Response::Error(format!("world {world} does not exist in this seed, which has {world_count} worlds"))
+            } else if room.resend_item(Corrector::Admin, world, source, key).await {
+                save_room(db_pool, &name, &room).await?;
+                Response::Ok
+            } else {
+                Response::Error(format!("world {world} has not received an item from world {source} at location key 0x{key:08x}"))
+            }
+        } else {
+            Response::Error(format!("there is no room named {name:?}"))
+        },
+        Command::RemoveItem { room: name, world, source, key } => if let Some(room) = rooms.state().await.1.get(&name) {
+            let mut room = room.write().await;
+            if room.remove_item(Corrector::Admin, world, source, key).await {
+                save_room(db_pool, &name, &room).await?;
+                Response::Ok
+            } else {
+                Response::Error(format!("world {world} has not received an item from world {source} at location key 0x{key:08x}"))
+            }
+        } else {
+            Response::Error(format!("there is no room named {name:?}"))
+        },
+        Command::InjectItem { room: name, world, source, kind } => if let Some(room) = rooms.state().await.1.get(&name) {
+            let mut room = room.write().await;
+            if let Some(world_count) = room.world_count.filter(|&world_count| world > world_count) {
+                Response::Error(format!("world {world} does not exist in this seed, which has {world_count} worlds"))
+            } else {
+                room.inject_item(Corrector::Admin, world, source, kind).await;
+                save_room(db_pool, &name, &room).await?;
+                Response::Ok
+            }
+        } else {
+            Response::Error(format!("there is no room named {name:?}"))
+        },
+        Command::Corrections { room } => if let Some(room) = rooms.state().await.1.get(&room) {
+            Response::Corrections(room.read().await.queue_corrections.clone())
+        } else {
+            Response::Error(format!("there is no room named {room:?}"))
+        },
+    })
+}
+
+pub(crate) async fn listen(path: &Path, db_pool: SqlitePool, rooms: ctrlflow::Handle<Rooms>) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let db_pool = db_pool.clone();
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            let res = async {
+                let command = Command::read(&mut stream).await?;
+                let response = match handle(&db_pool, &rooms, command).await {
+                    Ok(response) => response,
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                response.write(&mut stream).await?;
+                Ok::<_, Error>(())
+            }.await;
+            if let Err(e) = res {
+                eprintln!("{} error in admin session: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+            }
+        });
+    }
+}
+
+fn format_queue(queue: &[Item]) -> String {
+    if queue.is_empty() {
+        "    (empty)".to_owned()
+    } else {
+        queue.iter().map(|item| format!("    from world {}: item 0x{:04x} (location key 0x{:08x})", item.source, item.kind, item.key)).join("\n")
+    }
+}
+
+/// Sends a command to the running server and prints the response.
+pub(crate) async fn client(path: &Path, command: Command) -> Result<(), Error> {
+    let mut stream = UnixStream::connect(path).await?;
+    command.write(&mut stream).await?;
+    match Response::read(&mut stream).await? {
+        Response::Ok => {}
+        Response::Error(msg) => return Err(Error::Admin(msg)),
+        Response::Rooms(rooms) => for (name, RoomConnections { clients, spectators, owners, locked }) in rooms {
+            println!("{name} ({} client{}{})", clients.len(), if clients.len() == 1 { "" } else { "s" }, if locked { ", locked" } else { "" });
+            for (socket_id, player) in clients {
+                let owner = if owners.contains(&socket_id) { ", owner" } else { "" };
+                match player {
+                    Some(player) if player.name == Player::DEFAULT_NAME => println!("    socket {socket_id}: world {}, unnamed{owner}", player.world),
+                    Some(player) => println!("    socket {socket_id}: world {}, {}{owner}", player.world, render_filename(player.name)),
+                    None => println!("    socket {socket_id}: no world{owner}"),
+                }
+            }
+            for socket_id in spectators {
+                println!("    socket {socket_id}: spectator{}", if owners.contains(&socket_id) { ", owner" } else { "" });
+            }
+        },
+        Response::Queues { base_queue, player_queues } => {
+            println!("base queue:");
+            println!("{}", format_queue(&base_queue));
+            for (world, queue) in player_queues {
+                println!("world {world}:");
+                println!("{}", format_queue(&queue));
+            }
+        }
+        Response::ItemLog { items, csv } => if csv {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for item in items {
+                writer.serialize(ItemLogEntry::from(item))?;
+            }
+            writer.flush()?;
+        } else {
+            serde_json::to_writer_pretty(std::io::stdout(), &items.into_iter().map(ItemLogEntry::from).collect_vec())?;
+            println!();
+        },
+        Response::Corrections(corrections) => if corrections.is_empty() {
+            println!("no corrections");
+        } else {
+            for QueueCorrection { time, by, kind, world, item } in corrections {
+                let by = match by {
+                    Corrector::Admin => "server admin".to_owned(),
+                    Corrector::Owner(Some(owner_world)) => format!("owner (world {owner_world})"),
+                    Corrector::Owner(None) => "owner (no world)".to_owned(),
+                };
+                let kind = match kind {
+                    QueueCorrectionKind::Resend => "resent",
+                    QueueCorrectionKind::Remove => "removed",
+                    QueueCorrectionKind::Inject => "injected",
+                };
+                println!("{} {by} {kind} item 0x{:04x} from world {} (location key 0x{:08x}) for world {world}", time.format("%Y-%m-%d %H:%M:%S"), item.kind, item.source, item.key);
+            }
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! A read-only HTTP API which exposes room state as JSON, for race dashboards and bots that don't speak the binary protocol.
+
+use {
+    std::{
+        collections::{
+            BTreeMap,
+            HashSet,
+        },
+        convert::Infallible as Never,
+        net::{
+            IpAddr,
+            Ipv6Addr,
+        },
+        num::NonZeroU8,
+        sync::Arc,
+    },
+    chrono::prelude::*,
+    rocket::{
+        Build,
+        Rocket,
+        State,
+        http::Status,
+        request::{
+            self,
+            FromRequest,
+            Request,
+        },
+        serde::json::{
+            Json,
+            Value,
+            json,
+        },
+    },
+    serde::Serialize,
+    tokio::sync::Mutex,
+    multiworld::{
+        Player,
+        RoomInfo,
+        render_filename,
+        server::{
+            Rooms,
+            rate_limit::JoinLimiter,
+        },
+        verify_password,
+    },
+};
+
+/// The token which grants access to all rooms regardless of their passwords, if configured.
+struct ApiToken(Option<String>);
+
+/// Compares the given credentials to the API token without revealing how much of it they got right through the response time.
+fn is_api_token(credentials: &str, api_token: &str) -> bool {
+    credentials.len() == api_token.len() && credentials.bytes().zip(api_token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The credentials given in the `Authorization: Bearer …` header of a request, which can be either a room password or the API token.
+struct Credentials(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Credentials {
+    type Error = Never;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Never> {
+        request::Outcome::Success(Self(req.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer ")).map(str::to_owned)))
+    }
+}
+
+#[derive(Serialize)]
+struct RoomState {
+    created: DateTime<Utc>,
+    password_protected: bool,
+    locked: bool,
+    /// The number of worlds in the seed, if known from a spoiler log.
+    world_count: Option<NonZeroU8>,
+    /// Claimed worlds, including those whose players are currently disconnected but may resume their sessions.
+    players: BTreeMap<NonZeroU8, PlayerState>,
+    num_unassigned_clients: usize,
+    num_spectators: usize,
+}
+
+#[derive(Serialize)]
+struct PlayerState {
+    /// The player's file name, or `null` if they haven't set one or are disconnected.
+    name: Option<String>,
+    connected: bool,
+    /// The number of items this world has sent to any world, including itself.
+    items_sent: usize,
+    /// The number of items in this world's queue, including those it has sent to itself.
+    items_received: usize,
+}
+
+/// Lists all rooms along with the same information shown in the lobby. This endpoint does not require authorization.
+#[rocket::get("/api/v1/rooms")]
+async fn rooms(rooms: &State<ctrlflow::Handle<Rooms>>) -> Json<BTreeMap<String, RoomInfo>> {
+    let mut list = BTreeMap::default();
+    for (name, room) in &rooms.state().await.1 {
+        list.insert(name.clone(), room.read().await.info());
+    }
+    Json(list)
+}
+
+/// Shows the claimed worlds, connection status, and item counts of a room. Requires the room password or the API token unless the room has no password.
+///
+/// Wrong passwords count towards the same rate limit as failed attempts to join the room.
+#[rocket::get("/api/v1/room/<name>")]
+async fn room(rooms: &State<ctrlflow::Handle<Rooms>>, api_token: &State<ApiToken>, join_limiter: &State<Arc<Mutex<JoinLimiter>>>, ip: IpAddr, credentials: Credentials, name: &str) -> Result<Json<RoomState>, Status> {
+    let room = rooms.state().await.1.get(name).cloned().ok_or(Status::NotFound)?;
+    let authorized = match (credentials.0, &api_token.0) {
+        (Some(credentials), Some(api_token)) if is_api_token(&credentials, api_token) => true,
+        (Some(password), _) => {
+            let password_hash = room.read().await.password_hash.clone();
+            if let Some(password_hash) = password_hash {
+                if join_limiter.lock().await.start_attempt(ip, name).is_err() { return Err(Status::TooManyRequests) }
+                // Argon2 is slow by design, so don't block the executor or hold the room lock while verifying
+                let matches = tokio::task::spawn_blocking(move || verify_password(&password_hash, &password)).await.map_err(|_| Status::InternalServerError)?;
+                if matches {
+                    join_limiter.lock().await.succeeded(ip);
+                } else {
+                    join_limiter.lock().await.failed(ip, name);
+                }
+                matches
+            } else {
+                true
+            }
+        }
+        (None, _) => room.read().await.password_hash.is_none(),
+    };
+    if !authorized { return Err(Status::Unauthorized) }
+    let room = room.read().await;
+    let item_log = room.item_log();
+    let connected_players = room.clients.values().filter_map(|&(player, _)| player).collect::<Vec<_>>();
+    let worlds = room.reservations.keys().copied().chain(connected_players.iter().map(|player| player.world)).collect::<HashSet<_>>();
+    Ok(Json(RoomState {
+        created: room.created,
+        password_protected: room.password_hash.is_some(),
+        locked: room.locked,
+        world_count: room.world_count,
+        players: worlds.into_iter().map(|world| {
+            let player = connected_players.iter().find(|player| player.world == world);
+            (world, PlayerState {
+                name: player.filter(|player| player.name != Player::DEFAULT_NAME).map(|player| render_filename(player.name)),
+                connected: player.is_some(),
+                items_sent: item_log.iter().filter(|item| item.source == world).count(),
+                items_received: room.player_queues.get(&world).unwrap_or(&room.base_queue).len(),
+            })
+        }).collect(),
+        num_unassigned_clients: room.clients.values().filter(|(player, _)| player.is_none()).count(),
+        num_spectators: room.spectators.len(),
+    }))
+}
+
+/// Reports errors as JSON rather than Rocket's default HTML error pages.
+#[rocket::catch(default)]
+fn default_catcher(status: Status, _: &Request<'_>) -> Json<Value> {
+    Json(json!({
+        "error": status.reason_lossy(),
+    }))
+}
+
+pub(crate) fn rocket(port: u16, rooms: ctrlflow::Handle<Rooms>, join_limiter: Arc<Mutex<JoinLimiter>>, api_token: Option<String>) -> Rocket<Build> {
+    rocket::custom(rocket::Config {
+        address: Ipv6Addr::UNSPECIFIED.into(),
+        log_level: rocket::config::LogLevel::Critical,
+        // shutdown is handled by the main server
+        shutdown: rocket::config::Shutdown {
+            ctrlc: false,
+            #[cfg(unix)] signals: HashSet::default(),
+            ..rocket::config::Shutdown::default()
+        },
+        port,
+        ..rocket::Config::release_default()
+    })
+    .manage(rooms)
+    .manage(join_limiter)
+    .manage(ApiToken(api_token))
+    .mount("/", rocket::routes![
+        room,
+        rooms,
+    ])
+    .register("/", rocket::catchers![
+        default_catcher,
+    ])
+}
@@ -3,209 +3,343 @@
 
 use {
     std::{
-        collections::HashMap,
+        collections::{
+            HashMap,
+            HashSet,
+        },
         convert::{
             Infallible as Never,
-            TryFrom as _,
+            TryInto as _,
         },
         net::Ipv6Addr,
-        pin::Pin,
+        num::NonZeroU8,
+        path::{
+            Path,
+            PathBuf,
+        },
         sync::Arc,
+        time::Duration,
     },
-    async_proto::Protocol as _,
+    async_proto::Protocol,
     chrono::prelude::*,
     futures::{
-        future::Future,
-        stream::{
-            Stream,
-            StreamExt as _,
-        },
+        future,
+        stream::TryStreamExt as _,
+    },
+    reqwest::Url,
+    sqlx::{
+        SqlitePool,
+        sqlite::SqliteConnectOptions,
     },
     tokio::{
+        fs,
         io,
-        net::{
-            TcpListener,
-            tcp::{
-                OwnedReadHalf,
-                OwnedWriteHalf,
-            },
-        },
+        net::TcpListener,
+        pin,
         select,
+        signal::ctrl_c,
         sync::{
-            Mutex,
             RwLock,
-            broadcast,
-            mpsc,
+            watch,
+        },
+        time::{
+            interval,
+            sleep,
+        },
+    },
+    tokio_rustls::{
+        TlsAcceptor,
+        rustls::{
+            self,
+            Certificate,
+            PrivateKey,
+            ServerConfig,
         },
     },
-    tokio_stream::wrappers::ReceiverStream,
     multiworld::{
-        LobbyClientMessage,
-        Player,
+        EventSender,
+        Item,
+        Reservation,
         Room,
-        RoomClientMessage,
+        SeedInfo,
         ServerMessage,
+        server::{
+            self,
+            RoomListDelta,
+            Rooms,
+            Server,
+            accept_connections,
+            delete_room,
+            save_room,
+        },
     },
 };
+#[cfg(unix)] use tokio::signal::unix::{
+    SignalKind,
+    signal,
+};
 
-#[derive(Debug, thiserror::Error)]
-enum SessionError {
-    #[error(transparent)] Read(#[from] async_proto::ReadError),
-    #[error(transparent)] Write(#[from] async_proto::WriteError),
-    #[error("protocol version mismatch: client is version {0} but we're version {}", multiworld::VERSION)]
-    VersionMismatch(u8),
+#[cfg(unix)] mod admin;
+mod http;
+mod webhook;
+
+/// The format in which items were stored before their target worlds and timestamps were recorded.
+#[derive(Clone, Copy, Protocol)]
+struct LegacyItem {
+    source: NonZeroU8,
+    key: u32,
+    kind: u16,
 }
 
-async fn client_session(rooms_handle: ctrlflow::Handle<Rooms>, socket_id: multiworld::SocketId, mut reader: OwnedReadHalf, writer: Arc<Mutex<OwnedWriteHalf>>) -> Result<(), SessionError> {
-    macro_rules! error {
-        ($($msg:tt)*) => {{
-            let msg = format!($($msg)*);
-            ServerMessage::Error(msg).write(&mut *writer.lock().await).await?;
-            return Ok(())
-        }};
+impl LegacyItem {
+    fn upgrade(self, target: NonZeroU8, time: DateTime<Utc>) -> Item {
+        let Self { source, key, kind } = self;
+        Item { source, target, key, kind, time }
     }
+}
 
-    multiworld::VERSION.write(&mut *writer.lock().await).await?;
-    let client_version = u8::read(&mut reader).await?;
-    if client_version != multiworld::VERSION { return Err::<(), _>(SessionError::VersionMismatch(client_version)) }
-    let (mut room_tx, mut rooms, mut room_stream) = {
-        // finish handshake by sending room list (treated as a single packet)
-        let mut writer = writer.lock().await;
-        let (init, stream) = rooms_handle.stream().await;
-        let (tx, rooms) = init.clone();
-        u64::try_from(rooms.len()).expect("too many rooms").write(&mut *writer).await?;
-        for room_name in rooms.keys() {
-            room_name.write(&mut *writer).await?;
+/// Resolves when the server is asked to shut down, i.e. on SIGTERM (sent by systemd) or Ctrl+C.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)] {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        select! {
+            res = ctrl_c() => res,
+            _ = sigterm.recv() => Ok(()),
         }
-        (tx, rooms, stream)
-    };
-    let room = {
-        let mut read = LobbyClientMessage::read(&mut reader);
-        loop {
-            select! {
-                new_room = room_stream.recv() => match new_room {
-                    Ok(NewRoom { name, room }) => {
-                        ServerMessage::NewRoom(name.clone()).write(&mut *writer.lock().await).await?;
-                        rooms.insert(name, room);
-                    }
-                    Err(broadcast::error::RecvError::Closed) => unreachable!("room list should be maintained indefinitely"),
-                    Err(broadcast::error::RecvError::Lagged(_)) => {
-                        let (init, stream) = rooms_handle.stream().await;
-                        (room_tx, rooms) = init.clone();
-                        room_stream = stream;
-                    }
-                },
-                msg = &mut read => match msg? {
-                    LobbyClientMessage::JoinRoom { name, password } => if let Some(room) = rooms.get(&name) {
-                        if room.read().await.password != password { error!("wrong password for room {name:?}") }
-                        if room.read().await.clients.len() >= u8::MAX.into() { error!("room {name:?} is full") }
-                        {
-                            let mut room = room.write().await;
-                            room.add_client(socket_id, Arc::clone(&writer)).await;
-                            let mut players = Vec::<Player>::default();
-                            let mut num_unassigned_clients = 0;
-                            for &(player, _) in room.clients.values() {
-                                if let Some(player) = player {
-                                    players.insert(players.binary_search_by_key(&player.world, |p| p.world).expect_err("duplicate world number"), player);
-                                } else {
-                                    num_unassigned_clients += 1;
-                                }
-                            }
-                            ServerMessage::EnterRoom { players, num_unassigned_clients }.write(&mut *writer.lock().await).await?;
-                        }
-                        break Arc::clone(room)
-                    } else {
-                        error!("there is no room named {name:?}")
-                    },
-                    LobbyClientMessage::CreateRoom { name, password } => {
-                        //TODO disallow creating new rooms if preparing for reboot? (or at least warn)
-                        if name.is_empty() { error!("room name must not be empty") }
-                        if name.chars().count() >= 64 { error!("room name too long (maximum 64 characters)") }
-                        if name.contains('\0') { error!("room name must not contain null characters") }
-                        if password.chars().count() >= 64 { error!("room password too long (maximum 64 characters)") }
-                        if password.contains('\0') { error!("room password must not contain null characters") }
-                        if rooms.contains_key(&name) { error!("a room with this name already exists") }
-                        let mut clients = HashMap::default();
-                        clients.insert(socket_id, (None, Arc::clone(&writer)));
-                        let room = Arc::new(RwLock::new(Room {
-                            password, clients,
-                            base_queue: Vec::default(),
-                            player_queues: HashMap::default(),
-                        }));
-                        room_tx.send(NewRoom { name, room: Arc::clone(&room) }).await.expect("room list should be maintained indefinitely");
-                        //TODO automatically delete rooms after 7 days of inactivity (reduce to 24 hours after backup system is implemented, to reduce room list clutter)
-                        ServerMessage::EnterRoom {
-                            players: Vec::default(),
-                            num_unassigned_clients: 1,
-                        }.write(&mut *writer.lock().await).await?;
-                        break room
-                    }
-                },
-            }
-        }
-    };
+    }
+    #[cfg(not(unix))] { ctrl_c().await }
+}
+
+/// Periodically deletes rooms which have had no connected clients or spectators for longer than `timeout`.
+async fn expire_rooms(db_pool: SqlitePool, rooms: ctrlflow::Handle<Rooms>, timeout: chrono::Duration) -> sqlx::Result<Never> {
+    let mut interval = interval(Duration::from_secs(60));
     loop {
-        match RoomClientMessage::read(&mut reader).await? {
-            RoomClientMessage::PlayerId(id) => if !room.write().await.load_player(socket_id, id).await {
-                error!("world {id} is already taken")
-            },
-            RoomClientMessage::ResetPlayerId => room.write().await.unload_player(socket_id).await,
-            RoomClientMessage::PlayerName(name) => if !room.write().await.set_player_name(socket_id, name).await {
-                error!("please claim a world before setting your player name")
-            },
-            RoomClientMessage::SendItem { key, kind, target_world } => if !room.write().await.queue_item(socket_id, key, kind, target_world).await {
-                error!("please claim a world before sending items")
-            },
+        interval.tick().await;
+        let (room_tx, room_list) = rooms.state().await.clone();
+        for (name, room) in room_list {
+            // hold the write lock until the room is deleted so no client can join in the meantime
+            let room = room.write().await;
+            if room.clients.is_empty() && room.spectators.is_empty() && Utc::now() - room.last_activity >= timeout {
+                delete_room(&db_pool, &room_tx, name).await?;
+            }
         }
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-struct Rooms;
-
-#[derive(Debug, Clone)]
-struct NewRoom {
-    name: String,
-    room: Arc<RwLock<Room>>,
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[cfg(unix)] #[error(transparent)] Csv(#[from] csv::Error),
+    #[error(transparent)] Io(#[from] io::Error),
+    #[error(transparent)] Json(#[from] serde_json::Error),
+    #[error(transparent)] Migrate(#[from] sqlx::migrate::MigrateError),
+    #[error(transparent)] PasswordHash(#[from] argon2::password_hash::Error),
+    #[error(transparent)] Read(#[from] async_proto::ReadError),
+    #[error(transparent)] Reqwest(#[from] reqwest::Error),
+    #[error(transparent)] Rocket(#[from] rocket::Error),
+    #[error(transparent)] Session(#[from] server::SessionError),
+    #[error(transparent)] Sql(#[from] sqlx::Error),
+    #[error(transparent)] Tls(#[from] rustls::Error),
+    #[error(transparent)] Write(#[from] async_proto::WriteError),
+    #[cfg(unix)]
+    #[error("error from server: {0}")]
+    Admin(String),
+    #[error("no private key found in {}", .0.display())]
+    MissingTlsKey(PathBuf),
 }
 
-impl<T> ctrlflow::Delta<(T, HashMap<String, Arc<RwLock<Room>>>)> for NewRoom {
-    fn apply(&self, state: &mut (T, HashMap<String, Arc<RwLock<Room>>>)) {
-        state.1.insert(self.name.clone(), self.room.clone());
-    }
+/// Loads a PEM-encoded certificate chain and private key for accepting TLS connections.
+async fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, Error> {
+    let certs = rustls_pemfile::certs(&mut &*fs::read(cert_path).await?)?.into_iter().map(Certificate).collect();
+    let key_pem = fs::read(key_path).await?;
+    let mut key_reader = &*key_pem;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => {}
+            None => return Err(Error::MissingTlsKey(key_path.to_owned())),
+        }
+    };
+    Ok(TlsAcceptor::from(Arc::new(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)))
 }
 
-impl ctrlflow::Key for Rooms {
-    type State = (mpsc::Sender<NewRoom>, HashMap<String, Arc<RwLock<Room>>>);
-    type Delta = NewRoom;
+#[derive(clap::Parser)]
+#[clap(version)]
+struct Args {
+    /// Path to the SQLite database in which rooms are persisted across restarts.
+    #[clap(long, default_value = "/var/lib/ootrmwd/rooms.sqlite")]
+    database: PathBuf,
+    /// Rooms without any connected clients are deleted after this many hours of inactivity.
+    #[clap(long, default_value_t = 7 * 24)]
+    room_expiry_hours: i64,
+    /// Number of seconds between receiving SIGTERM and shutting down, during which clients are warned and no new rooms can be created.
+    #[clap(long, default_value_t = 60)]
+    restart_delay: i64,
+    /// Port on which to accept WebSocket connections, e.g. from browser-based tools. If this is omitted, only the regular TCP port is used.
+    #[clap(long)]
+    websocket_port: Option<u16>,
+    /// Port on which to serve the read-only HTTP API for room state. If this is omitted, the API is disabled.
+    #[clap(long)]
+    http_port: Option<u16>,
+    /// A secret which grants access to every room through the HTTP API, regardless of room passwords.
+    #[clap(long, requires = "http-port")]
+    api_token: Option<String>,
+    /// A URL to which room events (such as items being sent) are POSTed as JSON. Can be given multiple times.
+    #[clap(long = "webhook")]
+    webhooks: Vec<Url>,
+    /// Path to a PEM file with the TLS certificate chain. If this is omitted, only unencrypted connections are accepted.
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// Path to a PEM file with the private key for the TLS certificate.
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Path to the Unix domain socket used by `ootrmwd admin` to talk to the running server.
+    #[cfg(unix)]
+    #[clap(long, default_value = "/run/ootrmwd/admin.sock")]
+    admin_socket: PathBuf,
+    #[cfg(unix)]
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+}
 
-    fn maintain(self, _: ctrlflow::RunnerInternal<Self>) -> Pin<Box<dyn Future<Output = (Self::State, Pin<Box<dyn Stream<Item = Self::Delta> + Send>>)> + Send>> {
-        Box::pin(async move {
-            let (tx, rx) = mpsc::channel(64);
-            ((tx, HashMap::default()), ReceiverStream::new(rx).boxed())
-        })
-    }
+#[cfg(unix)]
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Inspect or manage the running server.
+    Admin {
+        #[clap(subcommand)]
+        command: admin::Command,
+    },
 }
 
 #[wheel::main]
-async fn main() -> io::Result<Never> {
+async fn main(args: Args) -> Result<(), Error> {
+    let Args { database, room_expiry_hours, restart_delay, websocket_port, http_port, api_token, webhooks, tls_cert, tls_key, .. } = args;
+    #[cfg(unix)] if let Some(Subcommand::Admin { command }) = args.subcommand {
+        return admin::client(&args.admin_socket, command).await
+    }
+    let tls_acceptor = if let (Some(tls_cert), Some(tls_key)) = (tls_cert, tls_key) {
+        Some(tls_acceptor(&tls_cert, &tls_key).await?)
+    } else {
+        None
+    };
+    let db_pool = SqlitePool::connect_with(SqliteConnectOptions::default().filename(database).create_if_missing(true)).await?;
+    server::MIGRATOR.run(&db_pool).await?;
+    // hash any passwords that were stored in plaintext by older versions
+    for (name, password) in sqlx::query_as::<_, (String, String)>("SELECT name, password FROM rooms WHERE password_hash IS NULL AND password IS NOT NULL").fetch_all(&db_pool).await? {
+        sqlx::query("UPDATE rooms SET password = NULL, password_hash = ? WHERE name = ?").bind(multiworld::hash_password(&password)?).bind(name).execute(&db_pool).await?;
+    }
+    let events = webhook::start(webhooks)?;
     let rooms = ctrlflow::run(Rooms).await;
-    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, multiworld::PORT)).await?;
-    loop {
-        let (socket, _) = listener.accept().await?;
-        let socket_id = multiworld::socket_id(&socket);
-        let (reader, writer) = socket.into_split();
-        let writer = Arc::new(Mutex::new(writer));
+    {
+        // restore rooms from the previous run
+        let room_tx = rooms.state().await.0.clone();
+        let mut rows = sqlx::query_as::<_, (String, Option<String>, Vec<u8>, Vec<u8>, DateTime<Utc>, DateTime<Utc>, Option<Vec<u8>>, bool, Option<u8>, Option<Vec<u8>>, bool, Option<Vec<u8>>, Option<Vec<u8>>)>("SELECT name, password_hash, base_queue, player_queues, last_activity, created, reservations, item_metadata, world_count, owner_token, locked, queue_corrections, seed FROM rooms").fetch(&db_pool);
+        while let Some((name, password_hash, base_queue, player_queues, last_activity, created, reservations, item_metadata, world_count, owner_token, locked, queue_corrections, seed)) = rows.try_next().await? {
+            let mut reservations = if let Some(reservations) = reservations { HashMap::<_, Reservation>::read_sync(&mut &*reservations)? } else { HashMap::default() };
+            // nobody is connected yet, so give everyone the usual grace period to reconnect after the restart
+            for reservation in reservations.values_mut() {
+                reservation.start_expiry();
+            }
+            let (base_queue, player_queues) = if item_metadata {
+                (Vec::read_sync(&mut &*base_queue)?, HashMap::read_sync(&mut &*player_queues)?)
+            } else {
+                // the actual times are unknown, so use the room's creation time for all existing items
+                // Triforce pieces are in the base queue and go to every world, so record them as sent to the sender's own world
+                (
+                    Vec::<LegacyItem>::read_sync(&mut &*base_queue)?.into_iter().map(|item| item.upgrade(item.source, created)).collect(),
+                    HashMap::<NonZeroU8, Vec<LegacyItem>>::read_sync(&mut &*player_queues)?.into_iter().map(|(world, queue)| (world, queue.into_iter().map(|item| item.upgrade(world, created)).collect())).collect(),
+                )
+            };
+            let room = Room {
+                password_hash, last_activity, created, reservations, base_queue, player_queues, locked,
+                spectators: HashMap::default(),
+                clients: HashMap::default(),
+                world_count: world_count.and_then(NonZeroU8::new),
+                seed: if let Some(seed) = seed { Some(SeedInfo::read_sync(&mut &*seed)?) } else { None },
+                owner_token: owner_token.and_then(|token| token.try_into().ok()),
+                owners: HashSet::default(),
+                queue_corrections: if let Some(queue_corrections) = queue_corrections { Vec::read_sync(&mut &*queue_corrections)? } else { Vec::default() },
+                events: events.clone().map(|tx| EventSender::new(name.clone(), tx)),
+                chat_history: Vec::default(),
+            };
+            room_tx.send(RoomListDelta::New { name, room: Arc::new(RwLock::new(room)) }).await.expect("room list should be maintained indefinitely");
+        }
+    }
+    {
+        let db_pool = db_pool.clone();
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            let Err(e) = expire_rooms(db_pool, rooms, chrono::Duration::hours(room_expiry_hours)).await;
+            eprintln!("{} error deleting inactive rooms: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+        });
+    }
+    #[cfg(unix)] {
+        let admin_socket = args.admin_socket;
+        let db_pool = db_pool.clone();
         let rooms = rooms.clone();
         tokio::spawn(async move {
-            if let Err(e) = client_session(rooms.clone(), socket_id, reader, writer).await {
-                eprintln!("{} error in client session: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+            if let Err(e) = admin::listen(&admin_socket, db_pool, rooms).await {
+                eprintln!("{} error in admin socket: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
             }
-            for room in rooms.state().await.1.values() {
-                if room.read().await.has_client(socket_id) {
-                    room.write().await.remove_client(socket_id).await;
-                }
+        });
+    }
+    let (restart_tx, restart_rx) = watch::channel(None);
+    let server = Server::new(db_pool.clone(), rooms.clone(), restart_rx, events);
+    if let Some(http_port) = http_port {
+        let rocket = http::rocket(http_port, rooms.clone(), server.join_limiter(), api_token);
+        tokio::spawn(async move {
+            if let Err(e) = rocket.launch().await {
+                eprintln!("{} error in HTTP API: {e:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
             }
         });
     }
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, multiworld::PORT)).await?;
+    let websocket_listener = if let Some(port) = websocket_port { Some(TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await?) } else { None };
+    let accept_tcp = accept_connections(listener, server.clone(), tls_acceptor.clone(), false);
+    let accept_websocket = async {
+        if let Some(listener) = websocket_listener {
+            accept_connections(listener, server, tls_acceptor, true).await
+        } else {
+            future::pending().await
+        }
+    };
+    let accept = async {
+        select! {
+            res = accept_tcp => res,
+            res = accept_websocket => res,
+        }
+    };
+    pin!(accept);
+    select! {
+        res = &mut accept => {
+            let Err(e) = res;
+            return Err(e.into())
+        }
+        res = shutdown_signal() => res?,
+    }
+    // stop creation of new rooms and warn connected clients, but keep accepting connections until the deadline so players can still join existing rooms
+    let eta = Utc::now() + chrono::Duration::seconds(restart_delay);
+    restart_tx.send_replace(Some(eta));
+    for room in rooms.state().await.1.values() {
+        room.write().await.write_all(&ServerMessage::PrepareRestart(eta)).await;
+    }
+    select! {
+        res = &mut accept => {
+            let Err(e) = res;
+            return Err(e.into())
+        }
+        () = sleep(Duration::from_secs(restart_delay.try_into().unwrap_or_default())) => {}
+    }
+    for (room_name, room) in &rooms.state().await.1 {
+        let mut room = room.write().await;
+        if !room.clients.is_empty() {
+            room.last_activity = Utc::now();
+            save_room(&db_pool, room_name, &room).await?;
+        }
+    }
+    db_pool.close().await;
+    Ok(())
 }
@@ -0,0 +1,66 @@
+//! Delivery of room events to the webhook URLs given with `--webhook`.
+
+use {
+    std::time::Duration,
+    chrono::prelude::*,
+    itertools::Itertools as _,
+    reqwest::{
+        Client,
+        Url,
+    },
+    tokio::{
+        sync::mpsc,
+        time::sleep,
+    },
+    multiworld::RoomEventReport,
+};
+
+/// How many events can be waiting to be delivered to each webhook before new events are dropped.
+const QUEUE_SIZE: usize = 256;
+/// How often delivery of an event is attempted before giving up on it.
+const MAX_ATTEMPTS: u32 = 5;
+/// How long to wait before retrying a failed delivery. Doubled after each attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Starts delivering events to the given webhooks in the background and returns the sender to use for [`multiworld::EventSender`]s. Returns `None` if there are no webhooks.
+///
+/// Each webhook has its own queue, so a slow or unreachable endpoint only delays its own events.
+pub(crate) fn start(urls: Vec<Url>) -> reqwest::Result<Option<mpsc::Sender<RoomEventReport>>> {
+    if urls.is_empty() { return Ok(None) }
+    let client = Client::builder()
+        .user_agent(concat!("ootrmwd/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let queues = urls.into_iter().map(|url| {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver(client.clone(), url.clone(), rx));
+        (url, tx)
+    }).collect_vec();
+    let (tx, mut rx) = mpsc::channel::<RoomEventReport>(QUEUE_SIZE);
+    tokio::spawn(async move {
+        while let Some(report) = rx.recv().await {
+            for (url, queue) in &queues {
+                if queue.try_send(report.clone()).is_err() {
+                    eprintln!("{} webhook queue for {url} is full, dropping {:?} in room {:?}", Utc::now().format("%Y-%m-%d %H:%M:%S"), report.event, report.room);
+                }
+            }
+        }
+    });
+    Ok(Some(tx))
+}
+
+async fn deliver(client: Client, url: Url, mut rx: mpsc::Receiver<RoomEventReport>) {
+    while let Some(report) = rx.recv().await {
+        let mut delay = INITIAL_RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match client.post(url.clone()).json(&report).send().await.and_then(|response| response.error_for_status()) {
+                Ok(_) => break,
+                Err(e) if attempt == MAX_ATTEMPTS => eprintln!("{} giving up on sending {:?} in room {:?} to webhook {url}: {e}", Utc::now().format("%Y-%m-%d %H:%M:%S"), report.event, report.room),
+                Err(_) => {
+                    sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+}